@@ -0,0 +1,548 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use hocon::Hocon;
+use log::warn;
+use tokio::sync::Mutex;
+
+use crate::errors::ConfigError;
+use crate::etcd_conf::{ConfClient, WatchResult};
+use crate::hocon_config::HoconClient;
+use crate::kv_backend::{is_unavailable, EtcdBackend, KVBackend};
+use crate::lease_keeper::MIN_KEEP_ALIVE_INTERVAL;
+use crate::reconnect::ReconnectPolicy;
+use crate::snapshot_file::SnapshotFile;
+use crate::tls::TlsConfig;
+
+const DEFAULT_LEASE_TTL: i64 = 10;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 5;
+
+/// Named settings of a `ConfClient`, all problems are reported at once by
+/// `build` as `ConfigError::InvalidSettings`.
+#[derive(Clone)]
+pub struct ConfClientBuilder {
+    endpoints: Vec<String>,
+    credentials: Option<(String, String)>,
+    tls: Option<TlsConfig>,
+    prefix: String,
+    watch_prefixes: Vec<String>,
+    watch_handler: Option<Arc<Mutex<dyn WatchResult + Send + Sync>>>,
+    lease_ttl: i64,
+    keep_alive_interval: Option<Duration>,
+    connect_timeout: u64,
+    reconnect_policy: ReconnectPolicy,
+    snapshot_file: Option<SnapshotFile>,
+}
+
+impl Default for ConfClientBuilder {
+    fn default() -> Self {
+        ConfClientBuilder {
+            endpoints: Vec::default(),
+            credentials: None,
+            tls: None,
+            prefix: String::default(),
+            watch_prefixes: Vec::default(),
+            watch_handler: None,
+            lease_ttl: DEFAULT_LEASE_TTL,
+            keep_alive_interval: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            reconnect_policy: ReconnectPolicy::default(),
+            snapshot_file: None,
+        }
+    }
+}
+
+impl ConfClientBuilder {
+    pub fn new() -> Self {
+        ConfClientBuilder::default()
+    }
+
+    /// Reads the settings from `section` of the configuration:
+    ///
+    /// ```text
+    /// etcd {
+    ///   endpoints = ["10.0.0.1:2379"]      # or a single string
+    ///   user = "root"                      # optional, requires `password`
+    ///   password = "secret"
+    ///   prefix = "local/node"
+    ///   watch_prefixes = ["local/group"]   # optional, see `watch_handler`
+    ///   lease_ttl = 10 seconds             # optional
+    ///   keep_alive_interval = 3 seconds    # optional, at least 100 milliseconds
+    ///   connect_timeout = 5 seconds        # optional
+    ///   snapshot_file = "/var/lib/node.json"  # optional, see `ConfClient::new_or_offline`
+    ///   tls {                              # optional
+    ///     ca_cert = "/etc/etcd/ca.pem"
+    ///     cert = "/etc/etcd/client.pem"    # optional, requires `key`
+    ///     key = "/etc/etcd/client.key"
+    ///     domain_name = "etcd.local"       # optional
+    ///   }
+    /// }
+    /// ```
+    ///
+    /// Durations given as bare numbers are seconds. Malformed values are reported
+    /// together with the problems `build` would find.
+    pub fn from_hocon(conf: &HoconClient, section: &str) -> Result<ConfClientBuilder> {
+        let mut s = Section {
+            conf,
+            path: section,
+            problems: Vec::default(),
+        };
+        let mut builder = ConfClientBuilder::new();
+
+        if let Some(endpoints) = s.strings("endpoints") {
+            builder = builder.endpoints(endpoints);
+        }
+        match (s.string("user"), s.string("password")) {
+            (Some(user), Some(password)) => builder = builder.auth(&user, &password),
+            (None, None) => {}
+            _ => s.problem("`user` and `password` must be set together".into()),
+        }
+        if let Some(prefix) = s.string("prefix") {
+            builder = builder.prefix(&prefix);
+        }
+        for prefix in s.strings("watch_prefixes").unwrap_or_default() {
+            builder = builder.watch_prefix(&prefix);
+        }
+        if let Some(lease_ttl) = s.seconds("lease_ttl") {
+            builder = builder.lease_ttl(lease_ttl as i64);
+        }
+        if let Some(interval) = s.duration("keep_alive_interval") {
+            builder = builder.keep_alive_interval(interval);
+        }
+        if let Some(connect_timeout) = s.seconds("connect_timeout") {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if let Some(path) = s.string("snapshot_file") {
+            builder = builder.snapshot_file(SnapshotFile::new(path));
+        }
+        if s.value("tls").is_some() {
+            let ca_cert = s.string("tls/ca_cert");
+            let identity = match (s.string("tls/cert"), s.string("tls/key")) {
+                (Some(cert), Some(key)) => Some((cert, key)),
+                (None, None) => None,
+                _ => {
+                    s.problem("`tls/cert` and `tls/key` must be set together".into());
+                    None
+                }
+            };
+            match TlsConfig::from_files(ca_cert, identity, s.string("tls/domain_name")) {
+                Ok(tls) => builder = builder.tls(tls),
+                Err(e) => s.problem(e.to_string()),
+            }
+        }
+
+        let mut problems = s.problems;
+        problems.extend(builder.problems(true, false));
+        if !problems.is_empty() {
+            return Err(ConfigError::InvalidSettings(problems).into());
+        }
+        Ok(builder)
+    }
+
+    pub fn endpoints(mut self, endpoints: Vec<String>) -> Self {
+        self.endpoints = endpoints;
+        self
+    }
+
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoints.push(endpoint.to_string());
+        self
+    }
+
+    pub fn auth(mut self, user: &str, password: &str) -> Self {
+        self.credentials = Some((user.to_string(), password.to_string()));
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Prefix watched for configuration changes.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Additional prefix whose changes `monitor` delivers to the `watch_handler`,
+    /// see `ConfClient::add_watch`.
+    pub fn watch_prefix(mut self, prefix: &str) -> Self {
+        self.watch_prefixes.push(prefix.to_string());
+        self
+    }
+
+    /// Receives the changes of all `watch_prefix`es, see `ConfClient::add_route`.
+    pub fn watch_handler(mut self, handler: Arc<Mutex<dyn WatchResult + Send + Sync>>) -> Self {
+        self.watch_handler = Some(handler);
+        self
+    }
+
+    /// TTL of the client lease in seconds.
+    pub fn lease_ttl(mut self, lease_ttl: i64) -> Self {
+        self.lease_ttl = lease_ttl;
+        self
+    }
+
+    /// See `ConfClient::set_keep_alive_interval`, must be at least
+    /// `MIN_KEEP_ALIVE_INTERVAL`.
+    pub fn keep_alive_interval(mut self, interval: Duration) -> Self {
+        self.keep_alive_interval = Some(interval);
+        self
+    }
+
+    /// Connect and request timeout in seconds.
+    pub fn connect_timeout(mut self, connect_timeout: u64) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// Persists the watched prefix and starts offline from it when etcd is
+    /// unreachable, see `ConfClient::with_backend_or_snapshot`.
+    pub fn snapshot_file(mut self, file: SnapshotFile) -> Self {
+        self.snapshot_file = Some(file);
+        self
+    }
+
+    pub async fn build(self) -> Result<ConfClient> {
+        self.check(true)?;
+        let connect = EtcdBackend::connect(
+            self.endpoints.clone(),
+            self.credentials.clone(),
+            self.tls.clone(),
+            self.connect_timeout,
+        );
+        let backend = match connect.await {
+            Ok(backend) => backend,
+            Err(e) if is_unavailable(&e) && self.snapshot_file.is_some() => {
+                warn!("Unable to connect to etcd: {}", e);
+                EtcdBackend::disconnected(
+                    self.endpoints.clone(),
+                    self.credentials.clone(),
+                    self.tls.clone(),
+                    self.connect_timeout,
+                )
+            }
+            Err(e) => return Err(e),
+        };
+        self.build_with_backend(Arc::new(backend)).await
+    }
+
+    /// Builds the client over `backend`, the connection settings are ignored.
+    pub async fn build_with_backend(self, backend: Arc<dyn KVBackend>) -> Result<ConfClient> {
+        self.check(false)?;
+        let prefix = self.prefix.clone();
+        let mut client = match self.snapshot_file.clone() {
+            Some(file) => {
+                ConfClient::with_backend_or_snapshot(backend, prefix, self.lease_ttl, file).await?
+            }
+            None => ConfClient::with_backend(backend, prefix, self.lease_ttl).await?,
+        };
+        self.configure(&mut client).await?;
+        Ok(client)
+    }
+
+    async fn configure(self, client: &mut ConfClient) -> Result<()> {
+        client.set_reconnect_policy(self.reconnect_policy);
+        if let Some(interval) = self.keep_alive_interval {
+            client.set_keep_alive_interval(interval);
+        }
+        if let Some(handler) = self.watch_handler {
+            for prefix in &self.watch_prefixes {
+                client.add_route(prefix, handler.clone()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, connect: bool) -> Result<()> {
+        let problems = self.problems(connect, true);
+        if !problems.is_empty() {
+            return Err(ConfigError::InvalidSettings(problems).into());
+        }
+        Ok(())
+    }
+
+    fn problems(&self, connect: bool, building: bool) -> Vec<String> {
+        let mut problems = Vec::default();
+        if connect {
+            if self.endpoints.is_empty() {
+                problems.push("no endpoints".to_string());
+            }
+            if self.endpoints.iter().any(|e| e.is_empty()) {
+                problems.push("empty endpoint".to_string());
+            }
+            if self.tls.is_some() {
+                for endpoint in self.endpoints.iter().filter(|e| e.starts_with("http://")) {
+                    problems.push(format!("endpoint `{}` is plain http with TLS", endpoint));
+                }
+            }
+            if matches!(&self.credentials, Some((user, _)) if user.is_empty()) {
+                problems.push("empty user".to_string());
+            }
+            if self.connect_timeout == 0 {
+                problems.push("connect timeout must be positive".to_string());
+            }
+        }
+        if self.prefix.is_empty() {
+            problems.push("no watch prefix".to_string());
+        }
+        for (i, prefix) in self.watch_prefixes.iter().enumerate() {
+            if prefix.is_empty() {
+                problems.push("empty watch prefix".to_string());
+            } else if *prefix == self.prefix || self.watch_prefixes[..i].contains(prefix) {
+                problems.push(format!("watch prefix `{}` is watched twice", prefix));
+            }
+        }
+        if building && !self.watch_prefixes.is_empty() && self.watch_handler.is_none() {
+            problems.push("watch prefixes without a watch handler".to_string());
+        }
+        if self.lease_ttl <= 0 {
+            problems.push(format!(
+                "lease TTL must be positive, got {}",
+                self.lease_ttl
+            ));
+        }
+        match self.keep_alive_interval {
+            Some(interval) if interval < MIN_KEEP_ALIVE_INTERVAL => {
+                problems.push(format!(
+                    "keep-alive interval {:?} must be at least {:?}",
+                    interval, MIN_KEEP_ALIVE_INTERVAL
+                ));
+            }
+            Some(interval) if self.lease_ttl > 0 && interval.as_secs() >= self.lease_ttl as u64 => {
+                problems.push(format!(
+                    "keep-alive interval {:?} must be shorter than the lease TTL",
+                    interval
+                ));
+            }
+            _ => {}
+        }
+        problems
+    }
+}
+
+/// Reads values of a configuration section, collecting the malformed ones.
+struct Section<'a> {
+    conf: &'a HoconClient,
+    path: &'a str,
+    problems: Vec<String>,
+}
+
+impl Section<'_> {
+    fn value(&self, key: &str) -> Option<Hocon> {
+        match self
+            .conf
+            .fetch_value_by_path(&format!("{}/{}", self.path, key))
+        {
+            Hocon::BadValue(_) | Hocon::Null => None,
+            value => Some(value),
+        }
+    }
+
+    fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    fn cast<T>(&mut self, key: &str, kind: &str, cast: impl Fn(&Hocon) -> Option<T>) -> Option<T> {
+        let value = self.value(key)?;
+        let res = cast(&value);
+        if res.is_none() {
+            self.problem(format!("`{}` must be {}", key, kind));
+        }
+        res
+    }
+
+    fn string(&mut self, key: &str) -> Option<String> {
+        self.cast(key, "a string", |v| match v {
+            Hocon::String(s) => Some(s.clone()),
+            _ => None,
+        })
+    }
+
+    /// A duration with a unit, or a bare number of seconds.
+    fn duration(&mut self, key: &str) -> Option<Duration> {
+        self.cast(key, "a duration", |v| {
+            let secs = match v {
+                Hocon::Integer(secs) => Some(*secs as f64),
+                Hocon::Real(secs) => Some(*secs),
+                _ => v.as_milliseconds().map(|ms| ms / 1000.0),
+            };
+            secs.filter(|secs| *secs >= 0.0 && secs.is_finite())
+                .map(Duration::from_secs_f64)
+        })
+    }
+
+    fn seconds(&mut self, key: &str) -> Option<u64> {
+        let duration = self.duration(key)?;
+        if duration.subsec_nanos() != 0 {
+            self.problem(format!("`{}` must be whole seconds", key));
+            return None;
+        }
+        Some(duration.as_secs())
+    }
+
+    fn strings(&mut self, key: &str) -> Option<Vec<String>> {
+        self.cast(key, "a string or a list of strings", |v| match v {
+            Hocon::String(s) => Some(vec![s.clone()]),
+            Hocon::Array(values) => values.iter().map(Hocon::as_string).collect(),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf_builder::ConfClientBuilder;
+    use crate::errors::ConfigError;
+    use crate::etcd_conf::{Operation, WatchEvent, WatchResult};
+    use crate::hocon_config::HoconClient;
+    use crate::kv_backend::KVBackend;
+    use crate::memory_backend::InMemoryBackend;
+    use crate::snapshot_file::SnapshotFile;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[derive(Default)]
+    struct Keys(Vec<String>);
+
+    #[async_trait]
+    impl WatchResult for Keys {
+        async fn notify(&mut self, _res: Operation) -> Result<()> {
+            Ok(())
+        }
+
+        async fn watch_batch(&mut self, events: Vec<WatchEvent>) -> Result<()> {
+            for event in events {
+                self.0.push(event.key.into_string()?);
+            }
+            Ok(())
+        }
+    }
+
+    fn problems<T>(res: Result<T>) -> Vec<String> {
+        match res.err().unwrap().downcast::<ConfigError>() {
+            Ok(ConfigError::InvalidSettings(problems)) => problems,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_from_hocon() -> Result<()> {
+        let conf = HoconClient::load("assets/test_etcd.conf")?;
+        let builder = ConfClientBuilder::from_hocon(&conf, "etcd")?;
+        let backend = InMemoryBackend::new();
+        backend.put(b"local/group/a", b"a", None).await?;
+        assert_eq!(
+            problems(
+                builder
+                    .clone()
+                    .build_with_backend(Arc::new(backend.clone()))
+                    .await
+            ),
+            vec!["watch prefixes without a watch handler"]
+        );
+
+        let keys = Arc::new(Mutex::new(Keys::default()));
+        let client = builder
+            .watch_handler(keys.clone())
+            .build_with_backend(Arc::new(backend))
+            .await?;
+        assert!(client.get_lease_id().is_some());
+        assert_eq!(client.watched_prefixes(), vec!["local/node", "local/group"]);
+        assert_eq!(keys.lock().await.0, vec!["local/group/a"]);
+
+        assert_eq!(
+            problems(ConfClientBuilder::from_hocon(&conf, "invalid")),
+            vec![
+                "`user` and `password` must be set together",
+                "`lease_ttl` must be a duration",
+                "`connect_timeout` must be whole seconds",
+                "`tls/cert` and `tls/key` must be set together",
+                "no endpoints",
+                "no watch prefix",
+                "keep-alive interval 10ms must be at least 100ms",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_validation() -> Result<()> {
+        let builder = ConfClientBuilder::new()
+            .endpoint("http://10.0.0.1:2379")
+            .tls(Default::default())
+            .watch_prefix("")
+            .watch_prefix("local/group")
+            .watch_prefix("local/group")
+            .lease_ttl(0)
+            .connect_timeout(0);
+        assert_eq!(
+            problems(builder.build().await),
+            vec![
+                "endpoint `http://10.0.0.1:2379` is plain http with TLS",
+                "connect timeout must be positive",
+                "no watch prefix",
+                "empty watch prefix",
+                "watch prefix `local/group` is watched twice",
+                "watch prefixes without a watch handler",
+                "lease TTL must be positive, got 0",
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_offline_build() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("conf-{}.json", uuid::Uuid::new_v4()));
+        let backend = InMemoryBackend::new();
+        backend.put(b"local/group/a", b"a", None).await?;
+        let builder = ConfClientBuilder::new()
+            .prefix("local/node")
+            .watch_prefix("local/group")
+            .snapshot_file(SnapshotFile::new(&path));
+        let keys = Arc::new(Mutex::new(Keys::default()));
+        let client = builder
+            .clone()
+            .watch_handler(keys.clone())
+            .build_with_backend(Arc::new(backend.clone()))
+            .await?;
+        assert!(!client.is_offline());
+        drop(client);
+
+        backend.set_available(false);
+        let keys = Arc::new(Mutex::new(Keys::default()));
+        let client = builder
+            .watch_handler(keys.clone())
+            .build_with_backend(Arc::new(backend))
+            .await?;
+        assert!(client.is_offline());
+        assert_eq!(client.watched_prefixes(), vec!["local/node", "local/group"]);
+        assert!(keys.lock().await.0.is_empty());
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::errors::ConfigError;
+use crate::kv_backend::{KVBackend, KVEntry, KVEventType, KVLeaderKey, KVLeaderStream};
+use crate::lease_keeper::{lease_lost, LeaseState};
+use crate::prefix_watch::PrefixWatch;
+
+const WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Election over etcd's election service, bound to the lease of the `ConfClient`
+/// it was created from. After `ClientEvent::LeaseLost` a new one has to be created.
+pub struct Election {
+    backend: Arc<dyn KVBackend>,
+    name: String,
+    lease_id: i64,
+    lease: watch::Receiver<LeaseState>,
+}
+
+impl Election {
+    pub fn new(
+        backend: Arc<dyn KVBackend>,
+        name: &str,
+        lease_id: i64,
+        lease: watch::Receiver<LeaseState>,
+    ) -> Election {
+        Election {
+            backend,
+            name: name.to_string(),
+            lease_id,
+            lease,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Waits until elected. Fails with `ConfigError::CampaignAborted` when the lease
+    /// is lost first.
+    pub async fn campaign(&self, value: &str) -> Result<Leadership> {
+        let key = tokio::select! {
+            key = self.backend.campaign(&self.name, value, self.lease_id) => key?,
+            _ = lease_lost(self.lease.clone()) => {
+                return Err(ConfigError::CampaignAborted(self.name.clone()).into())
+            }
+        };
+        info!(
+            "Elected as the leader of {} with key {}",
+            &self.name, &key.key
+        );
+        Ok(Leadership::spawn(
+            self.backend.clone(),
+            key,
+            self.lease.clone(),
+        ))
+    }
+
+    pub async fn leader(&self) -> Result<Option<KVEntry>> {
+        self.backend.leader(&self.name).await
+    }
+
+    pub async fn observe(&self) -> Result<Box<dyn KVLeaderStream>> {
+        self.backend.observe(&self.name).await
+    }
+}
+
+/// Held leadership. It is lost when the leader key is deleted or the lease is
+/// lost; dropping the handle resigns.
+pub struct Leadership {
+    backend: Arc<dyn KVBackend>,
+    key: KVLeaderKey,
+    state: watch::Receiver<bool>,
+    task: JoinHandle<()>,
+    resigned: bool,
+}
+
+impl Leadership {
+    fn spawn(
+        backend: Arc<dyn KVBackend>,
+        key: KVLeaderKey,
+        lease: watch::Receiver<LeaseState>,
+    ) -> Leadership {
+        let (sender, state) = watch::channel(true);
+        let task = tokio::spawn(track(backend.clone(), key.key.clone(), lease, sender));
+        Leadership {
+            backend,
+            key,
+            state,
+            task,
+            resigned: false,
+        }
+    }
+
+    pub fn key(&self) -> &KVLeaderKey {
+        &self.key
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.state.borrow()
+    }
+
+    /// Resolves once the leadership is lost. Cancel safe.
+    pub async fn lost(&mut self) {
+        while self.is_leader() {
+            if self.state.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub async fn proclaim(&self, value: &str) -> Result<()> {
+        if !self.is_leader() {
+            return Err(ConfigError::NotLeader(self.key.name.clone()).into());
+        }
+        self.backend.proclaim(&self.key, value).await
+    }
+
+    pub async fn resign(mut self) -> Result<()> {
+        self.resigned = true;
+        self.task.abort();
+        info!("Resigning from {}", &self.key.name);
+        self.backend.resign(&self.key).await
+    }
+}
+
+impl Drop for Leadership {
+    fn drop(&mut self) {
+        self.task.abort();
+        if self.resigned || !self.is_leader() {
+            return;
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let backend = self.backend.clone();
+            let key = self.key.clone();
+            runtime.spawn(async move {
+                if let Err(e) = backend.resign(&key).await {
+                    warn!("Unable to resign from {}: {}", &key.name, e);
+                }
+            });
+        }
+    }
+}
+
+async fn key_deleted(backend: Arc<dyn KVBackend>, key: &str) {
+    loop {
+        let mut watch = match PrefixWatch::new(backend.clone(), key).await {
+            Ok(watch) if !watch.contains(key.as_bytes()) => return,
+            Ok(watch) => watch,
+            Err(e) => {
+                warn!("Unable to watch leader key {}: {}", key, e);
+                tokio::time::sleep(WATCH_RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+        loop {
+            match watch.next().await {
+                Ok(Some(events)) => {
+                    if events
+                        .iter()
+                        .any(|e| e.event_type == KVEventType::Delete && e.kv.key == key)
+                    {
+                        return;
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Watch on leader key {} failed: {}", key, e);
+                    tokio::time::sleep(WATCH_RETRY_INTERVAL).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn track(
+    backend: Arc<dyn KVBackend>,
+    key: String,
+    lease: watch::Receiver<LeaseState>,
+    sender: watch::Sender<bool>,
+) {
+    tokio::select! {
+        _ = lease_lost(lease) => warn!("Leadership {} is lost with the lease", key),
+        _ = key_deleted(backend, &key) => warn!("Leadership {} is lost, the key was deleted", key),
+    }
+    sender.send_replace(false);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::etcd_conf::ConfClient;
+    use crate::kv_backend::KVBackend;
+    use crate::memory_backend::InMemoryBackend;
+    use anyhow::Result;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_election() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let first =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        let second =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+
+        let election = first.election("local/leader")?;
+        let mut observe = election.observe().await?;
+        let leadership = election.campaign("first").await?;
+        assert!(leadership.is_leader());
+        assert_eq!(observe.message().await?.unwrap().value, "first");
+
+        let other = second.election("local/leader")?;
+        let campaign = tokio::spawn(async move { other.campaign("second").await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!campaign.is_finished());
+
+        leadership.proclaim("first-updated").await?;
+        assert_eq!(observe.message().await?.unwrap().value, "first-updated");
+        assert_eq!(
+            election.leader().await?.unwrap().value,
+            "first-updated".to_string()
+        );
+
+        leadership.resign().await?;
+        let mut leadership = tokio::time::timeout(Duration::from_secs(1), campaign).await???;
+        assert_eq!(observe.message().await?.unwrap().value, "second");
+        assert_eq!(leadership.key().lease, second.get_lease_id().unwrap());
+
+        backend.lease_revoke(second.get_lease_id().unwrap()).await?;
+        tokio::time::timeout(Duration::from_secs(1), leadership.lost()).await?;
+        assert!(!leadership.is_leader());
+        assert!(leadership.proclaim("second-updated").await.is_err());
+        assert_eq!(election.leader().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_leadership_survives_interval_change() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        let leadership = client.election("local/leader")?.campaign("first").await?;
+
+        client.set_keep_alive_interval(Duration::from_millis(200));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(leadership.is_leader());
+        Ok(())
+    }
+}
@@ -29,4 +29,34 @@ pub enum ConfigLoadErrors {
 pub enum ConfigError {
     #[error("Key `{0}` doesn't exist!")]
     KeyDoesNotExist(String),
+    #[error("Lease `{0}` doesn't exist!")]
+    LeaseNotFound(i64),
+    #[error("Key `{0}` is modified more than once in a transaction!")]
+    DuplicateTxnKey(String),
+    #[error("Key-value backend is unavailable!")]
+    BackendUnavailable,
+    #[error("Unable to reconnect after {0} attempts!")]
+    ReconnectFailed(u32),
+    #[error("Client has no active lease!")]
+    NoLease,
+    #[error("Candidate of election `{0}` was removed before winning it!")]
+    CampaignAborted(String),
+    #[error("Not the leader of election `{0}`!")]
+    NotLeader(String),
+    #[error("Waiter on lock `{0}` was removed before acquiring it!")]
+    LockAborted(String),
+    #[error("Value of key `{0}` cannot be deserialized: {1}")]
+    DeserializeError(String, String),
+    #[error("Revision `{0}` is compacted or not yet reached!")]
+    RevisionUnavailable(i64),
+    #[error("Client is closed before the operations were applied!")]
+    ClientClosed,
+    #[error("Snapshot file holds prefix `{0}` instead of the watched one!")]
+    SnapshotPrefixMismatch(String),
+    #[error("Unable to read TLS file `{0}`: {1}")]
+    TlsFileUnreadable(String, String),
+    #[error("Invalid client settings: {}", .0.join(", "))]
+    InvalidSettings(Vec<String>),
+    #[error("Prefix `{0}` is already watched!")]
+    DuplicateWatch(String),
 }
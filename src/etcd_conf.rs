@@ -13,23 +13,132 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
  */
+use std::collections::BTreeMap;
+use std::future::Future;
 use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio::time::MissedTickBehavior;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use etcd_client::*;
+use futures::future::select_all;
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
 
+use crate::election::Election;
 use crate::errors::ConfigError;
+use crate::kv_backend::{
+    is_unavailable, paginate, prefix_end, EtcdBackend, KVBackend, KVBytes, KVCompare,
+    KVCompareTarget, KVEntry, KVEvent, KVEventType, KVGetOptions, KVTxn, KVTxnOp, KVWatchResponse,
+    MAX_TXN_OPS,
+};
+use crate::lease_keeper::{keep_alive_interval, LeaseKeeper};
+use crate::lock::{KVLockGuard, KVMutex, KVSemaphore};
+use crate::prefix_cache::PrefixCache;
+use crate::prefix_watch::PrefixWatch;
+use crate::reconnect::ReconnectPolicy;
+use crate::registry::{instance_key, ServiceDiscovery, ServiceInstance};
+use crate::snapshot_file::{SnapshotFile, SnapshotWriter};
+use crate::tls::TlsConfig;
+use crate::typed::from_entries;
 use log::{info, warn};
 
-const WATCH_WAIT_TTL: u64 = 1;
+const OPERATOR_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const OPERATION_ATTEMPTS: u32 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientEvent {
+    Disconnected {
+        error: String,
+    },
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    Reconnected,
+    /// The lease has expired and its keys are gone, a new lease is granted next.
+    LeaseLost {
+        lease_id: i64,
+    },
+}
+
+/// Change of a key under a watched prefix. Revision, version and lease describe
+/// the key after the change, for deletes they are zero except `mod_revision`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchEvent {
+    pub event_type: KVEventType,
+    pub key: KVBytes,
+    pub value: KVBytes,
+    /// `None` for created keys, and for changes found by re-reading the prefix
+    /// after a compaction or an offline start rather than watched.
+    pub prev_value: Option<KVBytes>,
+    pub create_revision: i64,
+    pub mod_revision: i64,
+    pub version: i64,
+    pub lease: i64,
+}
+
+impl WatchEvent {
+    pub fn is_create(&self) -> bool {
+        self.event_type == KVEventType::Put && self.version == 1
+    }
+}
+
+impl From<KVEvent> for WatchEvent {
+    fn from(event: KVEvent) -> Self {
+        WatchEvent {
+            event_type: event.event_type,
+            key: event.kv.key,
+            value: event.kv.value,
+            prev_value: event.prev_kv.map(|kv| kv.value),
+            create_revision: event.kv.create_revision,
+            mod_revision: event.kv.mod_revision,
+            version: event.kv.version,
+            lease: event.kv.lease,
+        }
+    }
+}
+
+impl From<WatchEvent> for Operation {
+    fn from(event: WatchEvent) -> Self {
+        match event.event_type {
+            KVEventType::Put => Operation::Set {
+                key: event.key,
+                value: event.value,
+                with_lease: event.lease != 0,
+            },
+            KVEventType::Delete => Operation::DelKey { key: event.key },
+        }
+    }
+}
 
 #[async_trait]
 pub trait WatchResult {
     async fn notify(&mut self, res: Operation) -> Result<()>;
+
+    /// Called for every change by the default `watch_batch`, passes it on to
+    /// `notify` unless overridden to use the revision metadata and the previous value.
+    async fn watch_event(&mut self, event: WatchEvent) -> Result<()> {
+        self.notify(event.into()).await
+    }
+
+    /// Called by `monitor` with all changes of one revision, e.g. of a
+    /// transaction or a `DelPrefix`, passes them on one by one to `watch_event`
+    /// unless overridden to apply them at once.
+    async fn watch_batch(&mut self, events: Vec<WatchEvent>) -> Result<()> {
+        for event in events {
+            self.watch_event(event).await?;
+        }
+        Ok(())
+    }
+
+    async fn client_event(&mut self, _event: ClientEvent) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -38,31 +147,220 @@ pub trait KVOperator {
 }
 
 pub struct ConfClient {
-    client: Client,
-    watcher: (Watcher, WatchStream),
+    backend: Arc<dyn KVBackend>,
+    prefix: String,
+    /// `None` while started offline from a snapshot file.
+    watcher: Option<PrefixWatch>,
     lease_timeout: i64,
-    lease_id: Option<i64>,
+    /// Overrides the refresh interval `LeaseKeeper` derives from the TTL.
+    keep_alive_interval: Option<Duration>,
+    keeper: Option<LeaseKeeper>,
+    leased_keys: BTreeMap<KVBytes, KVBytes>,
+    lost_keys: BTreeMap<KVBytes, KVBytes>,
+    atomic_operations: bool,
+    reconnect_policy: ReconnectPolicy,
+    submissions: mpsc::UnboundedReceiver<Submission>,
+    submitter: mpsc::UnboundedSender<Submission>,
+    stop: watch::Receiver<bool>,
+    stopper: Arc<watch::Sender<bool>>,
+    cache: Option<PrefixCache>,
+    snapshot_writer: Option<SnapshotWriter>,
+    /// Prefixes watched in addition to `prefix`, see `add_watch`.
+    routes: BTreeMap<String, RoutedWatch>,
+    /// Routes of an offline client, started once `monitor` is online, see `add_route`.
+    pending_routes: BTreeMap<String, Arc<Mutex<dyn WatchResult + Send + Sync>>>,
+    commands: mpsc::UnboundedReceiver<RouteCommand>,
+    commander: mpsc::UnboundedSender<RouteCommand>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+struct RoutedWatch {
+    watch: PrefixWatch,
+    handler: Arc<Mutex<dyn WatchResult + Send + Sync>>,
+}
+
+enum RouteCommand {
+    Add {
+        prefix: String,
+        handler: Arc<Mutex<dyn WatchResult + Send + Sync>>,
+        done: oneshot::Sender<Result<Vec<KVEntry>>>,
+    },
+    Remove {
+        prefix: String,
+        done: oneshot::Sender<Result<bool>>,
+    },
+}
+
+/// Adds and removes watched prefixes of a `ConfClient` while `monitor` runs,
+/// fails with `ConfigError::ClientClosed` once the client is dropped.
+#[derive(Clone)]
+pub struct WatchRouter {
+    commander: mpsc::UnboundedSender<RouteCommand>,
+}
+
+impl WatchRouter {
+    /// See `ConfClient::add_watch`.
+    pub async fn add(
+        &self,
+        prefix: &str,
+        handler: Arc<Mutex<dyn WatchResult + Send + Sync>>,
+    ) -> Result<Vec<KVEntry>> {
+        let (done, receiver) = oneshot::channel();
+        let _ = self.commander.send(RouteCommand::Add {
+            prefix: prefix.to_string(),
+            handler,
+            done,
+        });
+        receiver
+            .await
+            .unwrap_or_else(|_| Err(ConfigError::ClientClosed.into()))
+    }
+
+    /// See `ConfClient::remove_watch`.
+    pub async fn remove(&self, prefix: &str) -> Result<bool> {
+        let (done, receiver) = oneshot::channel();
+        let _ = self.commander.send(RouteCommand::Remove {
+            prefix: prefix.to_string(),
+            done,
+        });
+        receiver
+            .await
+            .unwrap_or_else(|_| Err(ConfigError::ClientClosed.into()))
+    }
+}
+
+/// Stops `monitor` of the client it was taken from, see `ConfClient::shutdown`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    stopper: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.stopper.send_replace(true);
+    }
+}
+
+struct Submission {
+    ops: Vec<Operation>,
+    done: oneshot::Sender<Result<OperationsOutcome>>,
+}
+
+/// Submits operations to a `ConfClient` from any task; `monitor` applies them as
+/// soon as they arrive, through `kv_operations`.
+#[derive(Clone)]
+pub struct ConfWriter {
+    submitter: mpsc::UnboundedSender<Submission>,
+}
+
+impl ConfWriter {
+    /// Queues the operations, the returned future resolves once they are applied.
+    pub fn submit(&self, ops: Vec<Operation>) -> WriteCompletion {
+        let (done, receiver) = oneshot::channel();
+        // a closed client drops `done`, which the completion reports
+        let _ = self.submitter.send(Submission { ops, done });
+        WriteCompletion { receiver }
+    }
+}
+
+/// Outcome of a `ConfWriter::submit`, fails with `ConfigError::ClientClosed` when
+/// the client is dropped first.
+pub struct WriteCompletion {
+    receiver: oneshot::Receiver<Result<OperationsOutcome>>,
+}
+
+impl Future for WriteCompletion {
+    type Output = Result<OperationsOutcome>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.receiver)
+            .poll(cx)
+            .map(|res| res.unwrap_or_else(|_| Err(ConfigError::ClientClosed.into())))
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub enum Operation {
     Set {
-        key: String,
-        value: String,
+        key: KVBytes,
+        value: KVBytes,
         with_lease: bool,
     },
     DelKey {
-        key: String,
+        key: KVBytes,
     },
     DelPrefix {
-        prefix: String,
+        prefix: KVBytes,
+    },
+    CreateIfAbsent {
+        key: KVBytes,
+        value: KVBytes,
+        with_lease: bool,
+    },
+    SetIfValue {
+        key: KVBytes,
+        expected: KVBytes,
+        value: KVBytes,
+        with_lease: bool,
+    },
+    SetIfModRevision {
+        key: KVBytes,
+        mod_revision: i64,
+        value: KVBytes,
+        with_lease: bool,
     },
+    DelIfValue {
+        key: KVBytes,
+        expected: KVBytes,
+    },
+    #[default]
     Nope,
 }
 
-impl Default for Operation {
-    fn default() -> Self {
-        Operation::Nope
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OperationsOutcome {
+    /// Conditional operations whose condition did not hold. Within a transaction
+    /// a single failed condition means that no operation was applied.
+    pub failed: Vec<Operation>,
+}
+
+impl OperationsOutcome {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Values read by `fetch_snapshot`, `ConfClient::watch_from` the `revision` misses no change.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VarSnapshot {
+    pub vars: BTreeMap<KVBytes, KVBytes>,
+    /// Keys of the `SingleVar` specs that don't exist.
+    pub missing: Vec<String>,
+    pub revision: i64,
+}
+
+/// Result of fetching a `VarPathSpec`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VarFetch {
+    /// Key and value of a `SingleVar`.
+    Var(KVBytes, KVBytes),
+    /// Pairs of a `Prefix` or `Range` in key order, possibly none.
+    Pairs(Vec<(KVBytes, KVBytes)>),
+    /// The key of a `SingleVar` doesn't exist.
+    NotFound(String),
+}
+
+impl VarFetch {
+    pub fn is_found(&self) -> bool {
+        !matches!(self, VarFetch::NotFound(_))
+    }
+
+    /// Fetched pairs, none for `NotFound`.
+    pub fn into_pairs(self) -> Vec<(KVBytes, KVBytes)> {
+        match self {
+            VarFetch::Var(key, value) => vec![(key, value)],
+            VarFetch::Pairs(pairs) => pairs,
+            VarFetch::NotFound(_) => Vec::default(),
+        }
     }
 }
 
@@ -70,6 +368,11 @@ impl Default for Operation {
 pub enum VarPathSpec {
     SingleVar(String),
     Prefix(String),
+    /// Keys in `[from, to)`, an empty `to` has no upper bound.
+    Range {
+        from: String,
+        to: String,
+    },
 }
 
 impl VarPathSpec {
@@ -93,54 +396,38 @@ impl VarPathSpec {
         )
     }
 
-    pub async fn get(&self, client: &mut Client) -> Result<(String, String)> {
-        match self {
-            VarPathSpec::SingleVar(key) => {
-                let resp = client.get(key.as_bytes(), None).await?;
-                match resp.kvs().first() {
-                    Some(res) => {
-                        info!(
-                            "Etcd Get: Key={}, Value={}",
-                            res.key_str()?,
-                            res.value_str()?
-                        );
-                        Ok((res.key_str()?.to_string(), res.value_str()?.to_string()))
-                    }
-                    None => {
-                        warn!("No value found for key: {:?}", key);
-                        Err(ConfigError::KeyDoesNotExist(key.clone()).into())
-                    }
-                }
-            }
-            _ => panic!("get method is only defined for SingleVar"),
+    pub fn new_range(from: &str, to: &str) -> VarPathSpec {
+        VarPathSpec::Range {
+            from: from.into(),
+            to: to.into(),
         }
     }
 
-    pub async fn get_prefix(&self, client: &mut Client) -> Result<Vec<(String, String)>> {
+    /// Key range `[from, to)` matched by the spec, an empty `to` has no upper bound.
+    pub fn bounds(&self) -> (Vec<u8>, Vec<u8>) {
         match self {
-            VarPathSpec::Prefix(key) => {
-                let resp = client
-                    .get(key.as_bytes(), Some(GetOptions::new().with_prefix()))
-                    .await?;
-                let mut result = Vec::default();
-                for kv in resp.kvs() {
-                    info!(
-                        "Etcd Get Prefix: Key={}, Value={}",
-                        kv.key_str()?,
-                        kv.value_str()?
-                    );
-                    result.push((kv.key_str()?.to_string(), kv.value_str()?.to_string()));
-                }
-                Ok(result)
+            VarPathSpec::SingleVar(key) => {
+                let mut to = key.as_bytes().to_vec();
+                to.push(0);
+                (key.as_bytes().to_vec(), to)
+            }
+            VarPathSpec::Prefix(prefix) => {
+                (prefix.as_bytes().to_vec(), prefix_end(prefix.as_bytes()))
             }
-            _ => panic!("get_prefix method is only defined for Prefix"),
+            VarPathSpec::Range { from, to } => (from.as_bytes().to_vec(), to.as_bytes().to_vec()),
         }
     }
+
+    /// Reads the spec, a missing `SingleVar` key is reported as `VarFetch::NotFound`.
+    pub async fn fetch(&self, client: &ConfClient) -> Result<VarFetch> {
+        let mut res = client.fetch_vars(std::slice::from_ref(self)).await?;
+        Ok(res.remove(0))
+    }
 }
 
 impl ConfClient {
     pub fn get_lease_id(&self) -> Option<i64> {
-        self.lease_id.clone()
+        self.keeper.as_ref().map(LeaseKeeper::lease_id)
     }
 
     pub async fn new(
@@ -150,280 +437,2476 @@ impl ConfClient {
         lease_timeout: i64,
         connect_timeout: u64,
     ) -> Result<ConfClient> {
-        info!("Connecting to {:?} etcd server", &uris);
-        let mut client = Client::connect(
-            uris,
-            Some({
-                let mut opts = ConnectOptions::new();
-                if let Some((user, password)) = credentials {
-                    opts = opts.with_user(user, password);
-                }
-                opts.with_timeout(Duration::from_secs(connect_timeout))
-            }),
-        )
-        .await?;
+        let backend = EtcdBackend::connect(uris, credentials, None, connect_timeout).await?;
+        ConfClient::with_backend(Arc::new(backend), path, lease_timeout).await
+    }
 
-        let watch_path = path.clone();
-        info!("Watching for {} for configuration changes", &watch_path);
-        let (watcher, watch_stream) = client
-            .watch(watch_path, Some(WatchOptions::new().with_prefix()))
-            .await?;
+    /// Like `new`, but connects over TLS, see `TlsConfig`.
+    pub async fn new_with_tls(
+        uris: Vec<String>,
+        credentials: Option<(String, String)>,
+        tls: TlsConfig,
+        path: String,
+        lease_timeout: i64,
+        connect_timeout: u64,
+    ) -> Result<ConfClient> {
+        let backend = EtcdBackend::connect(uris, credentials, Some(tls), connect_timeout).await?;
+        ConfClient::with_backend(Arc::new(backend), path, lease_timeout).await
+    }
+
+    /// Like `new`, but persists the watched prefix to `file` and, when etcd is
+    /// unreachable, starts offline from it, see `with_backend_or_snapshot`.
+    pub async fn new_or_offline(
+        uris: Vec<String>,
+        credentials: Option<(String, String)>,
+        path: String,
+        lease_timeout: i64,
+        connect_timeout: u64,
+        file: SnapshotFile,
+    ) -> Result<ConfClient> {
+        let connect =
+            EtcdBackend::connect(uris.clone(), credentials.clone(), None, connect_timeout);
+        let backend = match connect.await {
+            Ok(backend) => backend,
+            Err(e) if is_unavailable(&e) => {
+                warn!("Unable to connect to etcd: {}", e);
+                EtcdBackend::disconnected(uris, credentials, None, connect_timeout)
+            }
+            Err(e) => return Err(e),
+        };
+        ConfClient::with_backend_or_snapshot(Arc::new(backend), path, lease_timeout, file).await
+    }
+
+    /// Persists the watched prefix to `file`. When the backend is unavailable the
+    /// client starts offline from the saved snapshot instead: the cache serves it
+    /// and `monitor` reconciles with the store once connected, reporting the
+    /// differences as regular notifications.
+    /// Such a client has no lease until `monitor` grants one, leased writes and
+    /// `register` fail with `ConfigError::NoLease` until then.
+    pub async fn with_backend_or_snapshot(
+        backend: Arc<dyn KVBackend>,
+        path: String,
+        lease_timeout: i64,
+        file: SnapshotFile,
+    ) -> Result<ConfClient> {
+        match ConfClient::with_backend(backend.clone(), path.clone(), lease_timeout).await {
+            Ok(mut client) => {
+                client.persist_to(file).await?;
+                Ok(client)
+            }
+            Err(e) if is_unavailable(&e) => {
+                let snapshot = match file.load(&path).await? {
+                    Some(snapshot) => snapshot,
+                    None => return Err(e),
+                };
+                warn!(
+                    "Backend is unavailable: {}, starting from the snapshot of revision {}",
+                    e, snapshot.revision
+                );
+                let mut client = ConfClient::build(backend, path, lease_timeout, None, None);
+                let cache = PrefixCache::from_snapshot(snapshot);
+                client.snapshot_writer = Some(SnapshotWriter::spawn(
+                    file,
+                    &client.prefix,
+                    cache.snapshot(),
+                ));
+                client.cache = Some(cache);
+                Ok(client)
+            }
+            Err(e) => Err(e),
+        }
+    }
 
-        let lease = client.lease_grant(lease_timeout, None).await?;
+    pub async fn with_backend(
+        backend: Arc<dyn KVBackend>,
+        path: String,
+        lease_timeout: i64,
+    ) -> Result<ConfClient> {
+        info!("Watching for {} for configuration changes", &path);
+        let watcher = PrefixWatch::new(backend.clone(), &path).await?;
 
-        Ok(ConfClient {
-            client,
-            watcher: (watcher, watch_stream),
+        let lease_id = backend.lease_grant(lease_timeout).await?;
+        let keeper = LeaseKeeper::spawn(backend.clone(), lease_id, lease_timeout);
+        Ok(ConfClient::build(
+            backend,
+            path,
             lease_timeout,
-            lease_id: Some(lease.id()),
-        })
+            Some(watcher),
+            Some(keeper),
+        ))
     }
 
-    pub async fn fetch_vars(
+    fn build(
+        backend: Arc<dyn KVBackend>,
+        prefix: String,
+        lease_timeout: i64,
+        watcher: Option<PrefixWatch>,
+        keeper: Option<LeaseKeeper>,
+    ) -> ConfClient {
+        let (submitter, submissions) = mpsc::unbounded_channel();
+        let (stopper, stop) = watch::channel(false);
+        let (commander, commands) = mpsc::unbounded_channel();
+        ConfClient {
+            backend,
+            prefix,
+            watcher,
+            lease_timeout,
+            keep_alive_interval: None,
+            keeper,
+            leased_keys: BTreeMap::default(),
+            lost_keys: BTreeMap::default(),
+            atomic_operations: false,
+            reconnect_policy: ReconnectPolicy::default(),
+            submissions,
+            submitter,
+            stop,
+            stopper: Arc::new(stopper),
+            cache: None,
+            snapshot_writer: None,
+            routes: BTreeMap::default(),
+            pending_routes: BTreeMap::default(),
+            commands,
+            commander,
+        }
+    }
+
+    /// Watches `prefix` in addition to the client prefix, `monitor` delivers its
+    /// changes to `handler` rather than to its own `WatchResult`, which alone
+    /// receives the `ClientEvent`s. Returns the current entries of the prefix.
+    pub async fn add_watch(
         &mut self,
-        var_spec: &Vec<VarPathSpec>,
-    ) -> Result<Vec<(String, String)>> {
-        let mut res = Vec::default();
-        for v in var_spec {
-            match v {
-                VarPathSpec::SingleVar(_) => {
-                    let value_pair = v.get(&mut self.client).await?;
-                    res.push(value_pair);
-                }
-                VarPathSpec::Prefix(_) => {
-                    let mut value_pairs = v.get_prefix(&mut self.client).await?;
-                    res.append(&mut value_pairs);
-                }
-            }
+        prefix: &str,
+        handler: Arc<Mutex<dyn WatchResult + Send + Sync>>,
+    ) -> Result<Vec<KVEntry>> {
+        if prefix == self.prefix
+            || self.routes.contains_key(prefix)
+            || self.pending_routes.contains_key(prefix)
+        {
+            return Err(ConfigError::DuplicateWatch(prefix.to_string()).into());
         }
-        Ok(res)
+        let (watch, kvs) = PrefixWatch::with_snapshot(self.backend.clone(), prefix).await?;
+        info!("Watching for {} for configuration changes", prefix);
+        self.routes
+            .insert(prefix.to_string(), RoutedWatch { watch, handler });
+        Ok(kvs)
     }
 
-    pub async fn kv_operations(&mut self, ops: Vec<Operation>) -> Result<()> {
-        for op in ops {
-            match op {
-                Operation::Set {
-                    key,
-                    value,
-                    with_lease,
-                } => {
-                    self.client
-                        .put(
-                            key,
-                            value,
-                            Some({
-                                let mut opts = PutOptions::new();
-                                if with_lease {
-                                    opts = opts.with_lease(self.lease_id.unwrap());
-                                }
-                                opts
-                            }),
-                        )
-                        .await?;
-                }
-                Operation::DelKey { key } => {
-                    self.client.delete(key, None).await?;
-                }
-                Operation::DelPrefix { prefix } => {
-                    self.client
-                        .delete(prefix, Some(DeleteOptions::new().with_prefix()))
-                        .await?;
-                }
-                Operation::Nope => (),
-            }
+    /// Like `add_watch`, but the current entries go to `handler` as creations
+    /// through `watch_batch`. A client started offline adds the route once
+    /// `monitor` brings it online.
+    pub async fn add_route(
+        &mut self,
+        prefix: &str,
+        handler: Arc<Mutex<dyn WatchResult + Send + Sync>>,
+    ) -> Result<()> {
+        if !self.is_offline() {
+            return self.start_route(prefix, handler).await;
         }
+        if prefix == self.prefix
+            || self.routes.contains_key(prefix)
+            || self.pending_routes.contains_key(prefix)
+        {
+            return Err(ConfigError::DuplicateWatch(prefix.to_string()).into());
+        }
+        info!("Watching for {} once online", prefix);
+        self.pending_routes.insert(prefix.to_string(), handler);
         Ok(())
     }
 
-    pub async fn monitor(
+    async fn start_route(
         &mut self,
-        watch_result: Arc<Mutex<dyn WatchResult + Send + Sync>>,
-        kv_operator: Arc<Mutex<dyn KVOperator + Send + Sync>>,
+        prefix: &str,
+        handler: Arc<Mutex<dyn WatchResult + Send + Sync>>,
     ) -> Result<()> {
-        info!("Starting watching for changes on {:?}", self.watcher);
+        let kvs = self.add_watch(prefix, handler).await?;
+        let events = kvs
+            .into_iter()
+            .map(|kv| KVEvent {
+                event_type: KVEventType::Put,
+                kv,
+                prev_kv: None,
+            })
+            .collect();
+        self.deliver_routed(prefix, events).await
+    }
 
-        if self.lease_id.is_none() {
-            let lease = self.client.lease_grant(self.lease_timeout, None).await?;
-            self.lease_id = Some(lease.id());
+    /// Stops watching a prefix added with `add_watch` or `add_route`, false if it
+    /// was not.
+    pub async fn remove_watch(&mut self, prefix: &str) -> Result<bool> {
+        if self.pending_routes.remove(prefix).is_some() {
+            return Ok(true);
+        }
+        let mut route = match self.routes.remove(prefix) {
+            Some(route) => route,
+            None => return Ok(false),
+        };
+        if let Err(e) = route.watch.cancel().await {
+            warn!("Unable to cancel watch on {}: {}", prefix, e);
         }
+        info!("Stopped watching for {}", prefix);
+        Ok(true)
+    }
 
-        loop {
-            self.client.lease_keep_alive(self.lease_id.unwrap()).await?;
+    /// The client prefix followed by the ones added with `add_watch`, then those
+    /// waiting for an offline client to come online.
+    pub fn watched_prefixes(&self) -> Vec<String> {
+        let mut prefixes = vec![self.prefix.clone()];
+        prefixes.extend(self.routes.keys().cloned());
+        prefixes.extend(self.pending_routes.keys().cloned());
+        prefixes
+    }
 
-            let res = tokio::time::timeout(
-                Duration::from_secs(WATCH_WAIT_TTL),
-                self.watcher.1.message(),
-            )
-            .await;
+    pub fn router(&self) -> WatchRouter {
+        WatchRouter {
+            commander: self.commander.clone(),
+        }
+    }
 
-            if let Ok(res) = res {
-                if let Some(resp) = res? {
-                    if resp.canceled() {
-                        return Ok(());
-                    } else if resp.created() {
-                        info!("Etcd datcher was successfully deployed.");
-                    }
+    /// Tells whether the client runs from a snapshot file, not connected yet.
+    pub fn is_offline(&self) -> bool {
+        self.watcher.is_none()
+    }
 
-                    for event in resp.events() {
-                        if EventType::Delete == event.event_type() {
-                            if let Some(kv) = event.kv() {
-                                watch_result
-                                    .lock()
-                                    .await
-                                    .notify(Operation::DelKey {
-                                        key: kv.key_str()?.into(),
-                                    })
-                                    .await?;
-                            }
-                        }
+    /// Enables the cache and saves it to `file` now, then in the background after
+    /// the changes delivered by `monitor`, see `SnapshotWriter`.
+    pub async fn persist_to(&mut self, file: SnapshotFile) -> Result<()> {
+        let cache = self.enable_cache().await?;
+        let snapshot = cache.snapshot();
+        file.save(&self.prefix, &snapshot).await?;
+        self.snapshot_writer = Some(SnapshotWriter::spawn(file, &self.prefix, snapshot));
+        Ok(())
+    }
 
-                        if EventType::Put == event.event_type() {
-                            if let Some(kv) = event.kv() {
-                                watch_result
-                                    .lock()
-                                    .await
-                                    .notify(Operation::Set {
-                                        key: kv.key_str()?.to_string(),
-                                        value: kv.value_str()?.to_string(),
-                                        with_lease: kv.lease() != 0,
-                                    })
-                                    .await?;
-                            }
-                        }
-                    }
-                } else {
-                    return Ok(());
-                }
-            }
+    /// Starts mirroring the watched prefix, loaded at the revision the watcher is
+    /// at and then kept up to date by `monitor`.
+    pub async fn enable_cache(&mut self) -> Result<PrefixCache> {
+        if let Some(cache) = &self.cache {
+            return Ok(cache.clone());
+        }
+        let revision = match &self.watcher {
+            Some(watcher) => watcher.revision(),
+            None => return Err(ConfigError::BackendUnavailable.into()),
+        };
+        let prefix = self.prefix.as_bytes();
+        let options = KVGetOptions {
+            revision,
+            ..Default::default()
+        };
+        let resp = self
+            .backend
+            .get_range(prefix, &prefix_end(prefix), options)
+            .await?;
+        let cache = PrefixCache::new(resp.kvs, revision);
+        self.cache = Some(cache.clone());
+        Ok(cache)
+    }
 
-            let ops = kv_operator.lock().await.ops().await?;
-            self.kv_operations(ops).await?;
+    pub fn cache(&self) -> Option<&PrefixCache> {
+        self.cache.as_ref()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            stopper: self.stopper.clone(),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::etcd_conf::{ConfClient, KVOperator, Operation, VarPathSpec, WatchResult};
-    use anyhow::Result;
-    use async_trait::async_trait;
-    use log::info;
-    use std::sync::Arc;
-    use std::time::Duration;
-    use tokio::sync::Mutex;
+    /// Cancels the watcher, applies the pending `ConfWriter` submissions and revokes
+    /// the lease, so the leased keys disappear at once instead of after the TTL.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        info!("Shutting down watching of {}", &self.prefix);
+        // a dead stream must not keep the lease alive, the first error is
+        // returned once everything has been attempted
+        let mut errors = Vec::default();
+        if let Some(watcher) = &mut self.watcher {
+            if let Err(e) = watcher.cancel().await {
+                warn!("Unable to cancel watch on {}: {}", &self.prefix, e);
+                errors.push(e);
+            }
+        }
+        for (prefix, mut route) in std::mem::take(&mut self.routes) {
+            if let Err(e) = route.watch.cancel().await {
+                warn!("Unable to cancel watch on {}: {}", prefix, e);
+                errors.push(e);
+            }
+        }
+        while let Ok(submission) = self.submissions.try_recv() {
+            let res = self.kv_operations(submission.ops).await;
+            let _ = submission.done.send(res);
+        }
+        self.leased_keys.clear();
+        self.lost_keys.clear();
+        if let Some(writer) = self.snapshot_writer.take() {
+            let path = writer.file().path().to_path_buf();
+            if let Err(e) = writer.close().await {
+                warn!("Unable to save the snapshot to {:?}: {}", path, e);
+                errors.push(e);
+            }
+        }
+        if let Some(keeper) = self.keeper.take() {
+            info!("Revoking lease {}", keeper.lease_id());
+            if let Err(e) = self.backend.lease_revoke(keeper.lease_id()).await {
+                warn!("Unable to revoke lease {}: {}", keeper.lease_id(), e);
+                errors.push(e);
+            }
+        }
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
 
-    #[tokio::test]
-    async fn test_monitor() -> Result<()> {
-        let mut client = ConfClient::new(
-            vec!["10.0.0.1:2379".into()],
-            Some(("root".to_string(), "secret".to_string())),
-            "local/node".into(),
-            5,
-            10,
-        )
-        .await?;
+    /// Handle whose submissions are applied by `monitor` without waiting for the
+    /// `KVOperator` poll.
+    pub fn writer(&self) -> ConfWriter {
+        ConfWriter {
+            submitter: self.submitter.clone(),
+        }
+    }
 
-        client
-            .kv_operations(vec![
-                Operation::Set {
-                    key: "local/node".into(),
-                    value: "value".into(),
-                    with_lease: false,
-                },
-                Operation::Set {
-                    key: "local/node/leased".into(),
-                    value: "leased_value".into(),
-                    with_lease: true,
-                },
-            ])
-            .await?;
+    /// Election bound to the current lease.
+    pub fn election(&self, name: &str) -> Result<Election> {
+        let keeper = self.keeper.as_ref().ok_or(ConfigError::NoLease)?;
+        Ok(Election::new(
+            self.backend.clone(),
+            name,
+            keeper.lease_id(),
+            keeper.subscribe(),
+        ))
+    }
 
-        let res = client
-            .fetch_vars(&vec![VarPathSpec::SingleVar("local/node/leased".into())])
-            .await?;
+    /// Mutex whose waiters and holder are keys attached to the current lease.
+    pub fn mutex(&self, name: &str) -> Result<KVMutex> {
+        let keeper = self.keeper.as_ref().ok_or(ConfigError::NoLease)?;
+        Ok(KVMutex::new(
+            self.backend.clone(),
+            name,
+            keeper.lease_id(),
+            keeper.subscribe(),
+        ))
+    }
 
-        assert_eq!(
-            res,
-            vec![("local/node/leased".into(), "leased_value".into())]
-        );
+    pub async fn lock(&self, name: &str) -> Result<KVLockGuard> {
+        self.mutex(name)?.lock().await
+    }
 
-        let res = client
-            .fetch_vars(&vec![
-                VarPathSpec::Prefix("local/node".into()),
-                VarPathSpec::SingleVar("local/node/leased".into()),
-            ])
-            .await?;
+    pub fn semaphore(&self, name: &str, permits: usize) -> Result<KVSemaphore> {
+        let keeper = self.keeper.as_ref().ok_or(ConfigError::NoLease)?;
+        Ok(KVSemaphore::new(
+            self.backend.clone(),
+            name,
+            permits,
+            keeper.lease_id(),
+            keeper.subscribe(),
+        ))
+    }
 
-        assert_eq!(
+    /// Publishes the instance under `service` with the lease, so it leaves when the
+    /// client dies and is re-published after a lease loss.
+    pub async fn register(&mut self, service: &str, instance: &ServiceInstance) -> Result<()> {
+        let value = serde_json::to_string(instance)?;
+        self.kv_operations(vec![Operation::Set {
+            key: instance_key(service, &instance.id).into(),
+            value: value.into(),
+            with_lease: true,
+        }])
+        .await?;
+        Ok(())
+    }
+
+    pub async fn deregister(&mut self, service: &str, id: &str) -> Result<()> {
+        self.kv_operations(vec![Operation::DelKey {
+            key: instance_key(service, id).into(),
+        }])
+        .await?;
+        Ok(())
+    }
+
+    pub async fn discovery(&self, service: &str) -> Result<ServiceDiscovery> {
+        ServiceDiscovery::new(self.backend.clone(), service).await
+    }
+
+    /// Changes under `prefix` from now on. The stream does not borrow the client
+    /// and any number of them can be consumed concurrently; connectivity errors
+    /// are retried with the reconnect policy. Once it gives up, or on an error
+    /// that can't be retried such as a compaction, the error is the last item.
+    pub async fn watch(&self, prefix: &str) -> Result<impl Stream<Item = Result<WatchEvent>>> {
+        let batches = self.watch_batches(prefix).await?;
+        Ok(batches.flat_map(events))
+    }
+
+    /// Like `watch`, but yields all changes of one revision together.
+    pub async fn watch_batches(
+        &self,
+        prefix: &str,
+    ) -> Result<impl Stream<Item = Result<Vec<WatchEvent>>>> {
+        let watch = PrefixWatch::new(self.backend.clone(), prefix).await?;
+        Ok(self.batches(watch))
+    }
+
+    /// Like `watch`, but from the changes made after `revision`, e.g. the one of a
+    /// `VarSnapshot`. Fails with `ConfigError::RevisionUnavailable` once it is
+    /// compacted, also as the last item when that happens while watching.
+    pub async fn watch_from(
+        &self,
+        prefix: &str,
+        revision: i64,
+    ) -> Result<impl Stream<Item = Result<WatchEvent>>> {
+        let watch = PrefixWatch::from_revision(self.backend.clone(), prefix, revision).await?;
+        Ok(self.batches(watch).flat_map(events))
+    }
+
+    fn batches(&self, watch: PrefixWatch) -> impl Stream<Item = Result<Vec<WatchEvent>>> {
+        let backend = self.backend.clone();
+        let policy = self.reconnect_policy.clone();
+        // the state is `None` once the error ending the stream is yielded
+        let batches = stream::unfold(Some(watch), move |watch| {
+            let backend = backend.clone();
+            let policy = policy.clone();
+            async move {
+                let mut watch = watch?;
+                loop {
+                    match watch.next().await {
+                        Ok(Some(events)) => return Some((Ok(events), Some(watch))),
+                        Ok(None) => return None,
+                        Err(e) => {
+                            if let Err(e) = resume_watch(&*backend, &mut watch, &policy, e).await {
+                                warn!("Watch on {} is closed: {}", watch.prefix(), e);
+                                return Some((Err(e), None));
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        batches.flat_map(|batch| {
+            let batches = match batch {
+                Ok(events) => by_revision(events).into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(e)],
+            };
+            stream::iter(batches)
+        })
+    }
+
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect_policy = policy;
+    }
+
+    /// Refreshes the lease every `interval` instead of three times per TTL, but
+    /// not more often than `MIN_KEEP_ALIVE_INTERVAL`.
+    pub fn set_keep_alive_interval(&mut self, interval: Duration) {
+        self.keep_alive_interval = Some(interval);
+        if let Some(keeper) = &self.keeper {
+            keeper.set_interval(interval);
+        }
+    }
+
+    fn spawn_keeper(&self, lease_id: i64) -> LeaseKeeper {
+        let interval = self
+            .keep_alive_interval
+            .unwrap_or_else(|| keep_alive_interval(self.lease_timeout));
+        LeaseKeeper::spawn_with_interval(
+            self.backend.clone(),
+            lease_id,
+            self.lease_timeout,
+            interval,
+        )
+    }
+
+    /// Reads all specs at a single revision, one result per spec in order.
+    pub async fn fetch_vars(&self, var_spec: &[VarPathSpec]) -> Result<Vec<VarFetch>> {
+        Ok(self.read_specs(var_spec).await?.0)
+    }
+
+    /// Reads all specs at a single revision into an ordered map of distinct keys.
+    pub async fn fetch_snapshot(&self, var_spec: &[VarPathSpec]) -> Result<VarSnapshot> {
+        let (fetched, revision) = self.read_specs(var_spec).await?;
+        let mut snapshot = VarSnapshot {
+            revision,
+            ..Default::default()
+        };
+        for res in fetched {
+            match res {
+                VarFetch::NotFound(key) => snapshot.missing.push(key),
+                res => snapshot.vars.extend(res.into_pairs()),
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Reads up to `MAX_TXN_OPS` specs in a transaction and the rest one by one
+    /// at its revision.
+    async fn read_specs(&self, var_spec: &[VarPathSpec]) -> Result<(Vec<VarFetch>, i64)> {
+        let (first, rest) = var_spec.split_at(var_spec.len().min(MAX_TXN_OPS));
+        let success = first
+            .iter()
+            .map(|v| match v {
+                VarPathSpec::SingleVar(key) => KVTxnOp::Get {
+                    key: key.as_str().into(),
+                },
+                _ => {
+                    let (from, to) = v.bounds();
+                    KVTxnOp::GetRange {
+                        from: from.into(),
+                        to: to.into(),
+                    }
+                }
+            })
+            .collect();
+        let resp = self
+            .backend
+            .txn(KVTxn {
+                success,
+                ..Default::default()
+            })
+            .await?;
+
+        let mut gets = resp.gets;
+        let mut ranges = resp.ranges;
+        for v in rest {
+            let (from, to) = v.bounds();
+            let options = KVGetOptions {
+                revision: resp.revision,
+                ..Default::default()
+            };
+            let kvs = self.backend.get_range(&from, &to, options).await?.kvs;
+            match v {
+                VarPathSpec::SingleVar(_) => gets.push(kvs.into_iter().next()),
+                _ => ranges.push(kvs),
+            }
+        }
+
+        let mut gets = gets.into_iter();
+        let mut ranges = ranges.into_iter();
+        let mut res = Vec::default();
+        for v in var_spec {
+            res.push(match v {
+                VarPathSpec::SingleVar(key) => match gets.next().flatten() {
+                    Some(kv) => {
+                        info!("Etcd Get: Key={}, Value={}", kv.key, kv.value);
+                        VarFetch::Var(kv.key, kv.value)
+                    }
+                    None => {
+                        warn!("No value found for key: {:?}", key);
+                        VarFetch::NotFound(key.clone())
+                    }
+                },
+                _ => VarFetch::Pairs(
+                    ranges
+                        .next()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|kv| {
+                            info!("Etcd Get: Key={}, Value={}", kv.key, kv.value);
+                            (kv.key, kv.value)
+                        })
+                        .collect(),
+                ),
+            });
+        }
+        Ok((res, resp.revision))
+    }
+
+    /// Number of keys matched by the spec, without reading them.
+    pub async fn count(&self, spec: &VarPathSpec) -> Result<i64> {
+        let (from, to) = spec.bounds();
+        let options = KVGetOptions {
+            count_only: true,
+            ..Default::default()
+        };
+        Ok(self.backend.get_range(&from, &to, options).await?.count)
+    }
+
+    /// Pairs matched by the spec in key order, read `page_size` keys per request
+    /// at a single revision, see `kv_backend::paginate`.
+    pub fn scan(
+        &self,
+        spec: &VarPathSpec,
+        page_size: i64,
+    ) -> impl Stream<Item = Result<(KVBytes, KVBytes)>> {
+        let (from, to) = spec.bounds();
+        let options = KVGetOptions {
+            limit: page_size,
+            ..Default::default()
+        };
+        paginate(self.backend.clone(), from, to, options).map_ok(|kv| (kv.key, kv.value))
+    }
+
+    /// Keys matched by the spec, as `scan` does without transferring the values.
+    pub fn scan_keys(
+        &self,
+        spec: &VarPathSpec,
+        page_size: i64,
+    ) -> impl Stream<Item = Result<KVBytes>> {
+        let (from, to) = spec.bounds();
+        let options = KVGetOptions {
+            limit: page_size,
+            keys_only: true,
+            ..Default::default()
+        };
+        paginate(self.backend.clone(), from, to, options).map_ok(|kv| kv.key)
+    }
+
+    /// Deserializes the keys under `prefix` into `T`, see `typed::from_entries`.
+    pub async fn fetch_typed<T: DeserializeOwned>(&self, prefix: &str) -> Result<T> {
+        let snapshot = self.backend.get_prefix(prefix.as_bytes()).await?;
+        Ok(from_entries(prefix, snapshot.kvs)?)
+    }
+
+    /// When enabled, `kv_operations` (and therefore the operations returned by a
+    /// `KVOperator` in `monitor`) are submitted as a single transaction.
+    pub fn set_atomic_operations(&mut self, atomic: bool) {
+        self.atomic_operations = atomic;
+    }
+
+    /// Keys written with the lease, re-published under a new lease when it is lost.
+    pub fn leased_keys(&self) -> &BTreeMap<KVBytes, KVBytes> {
+        &self.leased_keys
+    }
+
+    fn forget(&mut self, key: &[u8]) {
+        self.leased_keys.remove(key);
+        self.lost_keys.remove(key);
+    }
+
+    fn remember(&mut self, op: &Operation) {
+        match op {
+            Operation::Set {
+                key,
+                value,
+                with_lease,
+            }
+            | Operation::CreateIfAbsent {
+                key,
+                value,
+                with_lease,
+            }
+            | Operation::SetIfValue {
+                key,
+                value,
+                with_lease,
+                ..
+            }
+            | Operation::SetIfModRevision {
+                key,
+                value,
+                with_lease,
+                ..
+            } => {
+                self.forget(key);
+                if *with_lease {
+                    self.leased_keys.insert(key.clone(), value.clone());
+                }
+            }
+            Operation::DelKey { key } | Operation::DelIfValue { key, .. } => self.forget(key),
+            Operation::DelPrefix { prefix } => {
+                self.leased_keys.retain(|k, _| !k.starts_with(prefix));
+                self.lost_keys.retain(|k, _| !k.starts_with(prefix));
+            }
+            Operation::Nope => (),
+        }
+    }
+
+    /// Fails with `ConfigError::NoLease` for leased writes without a live lease,
+    /// e.g. after `shutdown` or before `monitor` grants one to an offline client.
+    fn lease_for(&self, with_lease: bool) -> Result<Option<i64>> {
+        if !with_lease {
+            return Ok(None);
+        }
+        match &self.keeper {
+            Some(keeper) if !keeper.is_lost() => Ok(Some(keeper.lease_id())),
+            _ => Err(ConfigError::NoLease.into()),
+        }
+    }
+
+    fn txn_op(&self, op: &Operation) -> Result<Option<(Option<KVCompare>, KVTxnOp)>> {
+        let put = |key: &KVBytes, value: &KVBytes, with_lease: bool| -> Result<KVTxnOp> {
+            Ok(KVTxnOp::Put {
+                key: key.clone(),
+                value: value.clone(),
+                lease_id: self.lease_for(with_lease)?,
+            })
+        };
+        let compare = |key: &KVBytes, target| KVCompare {
+            key: key.clone(),
+            target,
+        };
+        Ok(match op {
+            Operation::Set {
+                key,
+                value,
+                with_lease,
+            } => Some((None, put(key, value, *with_lease)?)),
+            Operation::DelKey { key } => Some((None, KVTxnOp::Delete { key: key.clone() })),
+            Operation::DelPrefix { prefix } => Some((
+                None,
+                KVTxnOp::DeletePrefix {
+                    prefix: prefix.clone(),
+                },
+            )),
+            Operation::CreateIfAbsent {
+                key,
+                value,
+                with_lease,
+            } => Some((
+                Some(compare(key, KVCompareTarget::CreateRevision(0))),
+                put(key, value, *with_lease)?,
+            )),
+            Operation::SetIfValue {
+                key,
+                expected,
+                value,
+                with_lease,
+            } => Some((
+                Some(compare(key, KVCompareTarget::Value(expected.clone()))),
+                put(key, value, *with_lease)?,
+            )),
+            Operation::SetIfModRevision {
+                key,
+                mod_revision,
+                value,
+                with_lease,
+            } => Some((
+                Some(compare(key, KVCompareTarget::ModRevision(*mod_revision))),
+                put(key, value, *with_lease)?,
+            )),
+            Operation::DelIfValue { key, expected } => Some((
+                Some(compare(key, KVCompareTarget::Value(expected.clone()))),
+                KVTxnOp::Delete { key: key.clone() },
+            )),
+            Operation::Nope => None,
+        })
+    }
+
+    pub async fn kv_transaction(&mut self, ops: Vec<Operation>) -> Result<OperationsOutcome> {
+        let mut txn = KVTxn::default();
+        let mut conditional = Vec::default();
+        for op in &ops {
+            if let Some((compare, txn_op)) = self.txn_op(op)? {
+                if let Some(compare) = compare {
+                    // read the compared keys back on failure to tell which conditions failed
+                    txn.failure.push(KVTxnOp::Get {
+                        key: compare.key.clone(),
+                    });
+                    txn.compares.push(compare.clone());
+                    conditional.push((op.clone(), compare));
+                }
+                txn.success.push(txn_op);
+            }
+        }
+        if txn.success.is_empty() {
+            return Ok(OperationsOutcome::default());
+        }
+
+        let resp = self.backend.txn(txn).await?;
+        if resp.succeeded {
+            info!("Etcd Txn: applied at revision {}", resp.revision);
+            ops.iter().for_each(|op| self.remember(op));
+            return Ok(OperationsOutcome::default());
+        }
+
+        let failed: Vec<_> = conditional
+            .into_iter()
+            .zip(resp.gets.iter())
+            .filter(|((_, compare), entry)| !compare.matches(entry.as_ref()))
+            .map(|((op, _), _)| op)
+            .collect();
+        warn!("Etcd Txn: conditions failed for {:?}", failed);
+        Ok(OperationsOutcome { failed })
+    }
+
+    pub async fn kv_operations(&mut self, ops: Vec<Operation>) -> Result<OperationsOutcome> {
+        let mut outcome = OperationsOutcome::default();
+        self.apply_operations(&ops, &mut 0, &mut outcome).await?;
+        Ok(outcome)
+    }
+
+    /// Applies `ops` as `kv_operations` does, counting the applied ones in `applied`.
+    async fn apply_operations(
+        &mut self,
+        ops: &[Operation],
+        applied: &mut usize,
+        outcome: &mut OperationsOutcome,
+    ) -> Result<()> {
+        if self.atomic_operations {
+            *outcome = self.kv_transaction(ops.to_vec()).await?;
+            *applied += ops.len();
+            return Ok(());
+        }
+        for op in ops {
+            match op {
+                Operation::Set {
+                    key,
+                    value,
+                    with_lease,
+                } => {
+                    let lease_id = self.lease_for(*with_lease)?;
+                    self.backend.put(key, value, lease_id).await?;
+                }
+                Operation::DelKey { key } => {
+                    self.backend.delete(key).await?;
+                }
+                Operation::DelPrefix { prefix } => {
+                    self.backend.delete_prefix(prefix).await?;
+                }
+                Operation::Nope => (),
+                _ => {
+                    let mut res = self.kv_transaction(vec![op.clone()]).await?;
+                    outcome.failed.append(&mut res.failed);
+                    *applied += 1;
+                    continue;
+                }
+            }
+            self.remember(op);
+            *applied += 1;
+        }
+        Ok(())
+    }
+
+    /// Applies `ops`, reconnecting on connectivity errors and retrying only the
+    /// operations not applied yet, up to `OPERATION_ATTEMPTS` times. The outer
+    /// error tells that the connection could not be recovered.
+    async fn apply_recovering(
+        &mut self,
+        watch_result: &Arc<Mutex<dyn WatchResult + Send + Sync>>,
+        ops: Vec<Operation>,
+    ) -> Result<Result<OperationsOutcome>> {
+        let mut outcome = OperationsOutcome::default();
+        let mut applied = 0;
+        let mut attempt = 1;
+        loop {
+            match self
+                .apply_operations(&ops[applied..], &mut applied, &mut outcome)
+                .await
+            {
+                Ok(()) => return Ok(Ok(outcome)),
+                Err(e) if is_unavailable(&e) && attempt < OPERATION_ATTEMPTS => {
+                    self.recover(watch_result, e).await?;
+                    attempt += 1;
+                }
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+    }
+
+    async fn recover(
+        &mut self,
+        watch_result: &Arc<Mutex<dyn WatchResult + Send + Sync>>,
+        error: anyhow::Error,
+    ) -> Result<()> {
+        if !is_unavailable(&error) || self.reconnect_policy.exhausted(1) {
+            return Err(error);
+        }
+
+        warn!("Lost connection to etcd: {}", error);
+        watch_result
+            .lock()
+            .await
+            .client_event(ClientEvent::Disconnected {
+                error: error.to_string(),
+            })
+            .await?;
+
+        let mut attempt = 1;
+        loop {
+            if self.reconnect_policy.exhausted(attempt) {
+                return Err(ConfigError::ReconnectFailed(attempt - 1).into());
+            }
+            let delay = self.reconnect_policy.backoff(attempt);
+            watch_result
+                .lock()
+                .await
+                .client_event(ClientEvent::Reconnecting { attempt, delay })
+                .await?;
+            tokio::time::sleep(delay).await;
+
+            match self.reestablish().await {
+                Ok(()) => break,
+                Err(e) if is_unavailable(&e) => {
+                    warn!("Reconnect attempt {} failed: {}", attempt, e);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        info!("Reconnected to etcd");
+        watch_result
+            .lock()
+            .await
+            .client_event(ClientEvent::Reconnected)
+            .await
+    }
+
+    async fn apply_submission(
+        &mut self,
+        watch_result: &Arc<Mutex<dyn WatchResult + Send + Sync>>,
+        submission: Submission,
+    ) -> Result<()> {
+        match self.apply_recovering(watch_result, submission.ops).await {
+            Ok(res) => {
+                let _ = submission.done.send(res);
+                Ok(())
+            }
+            Err(e) => {
+                let _ = submission.done.send(Err(anyhow!("{:#}", e)));
+                Err(e)
+            }
+        }
+    }
+
+    async fn reestablish(&mut self) -> Result<()> {
+        // a lease expired while disconnected is reported by the keeper
+        self.backend.reconnect().await?;
+        if let Some(watcher) = &mut self.watcher {
+            watcher.resume().await?;
+        }
+        for route in self.routes.values_mut() {
+            route.watch.resume().await?;
+        }
+        Ok(())
+    }
+
+    async fn apply_command(&mut self, command: RouteCommand) {
+        match command {
+            RouteCommand::Add {
+                prefix,
+                handler,
+                done,
+            } => {
+                let _ = done.send(self.add_watch(&prefix, handler).await);
+            }
+            RouteCommand::Remove { prefix, done } => {
+                let _ = done.send(self.remove_watch(&prefix).await);
+            }
+        }
+    }
+
+    async fn deliver_routed(&mut self, prefix: &str, events: Vec<KVEvent>) -> Result<()> {
+        let handler = match self.routes.get(prefix) {
+            Some(route) => route.handler.clone(),
+            None => return Ok(()),
+        };
+        for batch in by_revision(events) {
+            handler.lock().await.watch_batch(batch).await?;
+        }
+        Ok(())
+    }
+
+    /// Starts the watch of a client started offline, the changes made since the
+    /// snapshot are delivered as notifications.
+    async fn go_online(
+        &mut self,
+        watch_result: &Arc<Mutex<dyn WatchResult + Send + Sync>>,
+    ) -> Result<()> {
+        let known = match &self.cache {
+            Some(cache) => cache
+                .snapshot()
+                .kvs
+                .values()
+                .map(|kv| (kv.key.clone(), kv.mod_revision))
+                .collect(),
+            None => BTreeMap::default(),
+        };
+        let (watcher, events) =
+            PrefixWatch::reconcile(self.backend.clone(), &self.prefix, known).await?;
+        info!(
+            "Watching {} online, {} changes since the snapshot",
+            &self.prefix,
+            events.len()
+        );
+        // a failure leaves the client offline, the remaining routes are retried
+        while let Some((prefix, handler)) = self.pending_routes.pop_first() {
+            if let Err(e) = self.start_route(&prefix, handler.clone()).await {
+                if !self.routes.contains_key(&prefix) {
+                    self.pending_routes.insert(prefix, handler);
+                }
+                return Err(e);
+            }
+        }
+        let revision = watcher.revision();
+        self.watcher = Some(watcher);
+        watch_result
+            .lock()
+            .await
+            .client_event(ClientEvent::Reconnected)
+            .await?;
+        self.deliver(watch_result, events, revision).await
+    }
+
+    async fn deliver(
+        &mut self,
+        watch_result: &Arc<Mutex<dyn WatchResult + Send + Sync>>,
+        events: Vec<KVEvent>,
+        revision: i64,
+    ) -> Result<()> {
+        if let Some(cache) = &self.cache {
+            cache.apply(&events, revision);
+            if let Some(writer) = &self.snapshot_writer {
+                writer.save(cache.snapshot());
+            }
+        }
+        for batch in by_revision(events) {
+            watch_result.lock().await.watch_batch(batch).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_lease(
+        &mut self,
+        watch_result: &Arc<Mutex<dyn WatchResult + Send + Sync>>,
+    ) -> Result<()> {
+        let lost = self
+            .keeper
+            .as_ref()
+            .filter(|keeper| keeper.is_lost())
+            .map(LeaseKeeper::lease_id);
+        if let Some(lease_id) = lost {
+            warn!("Lease {} is lost", lease_id);
+            self.keeper = None;
+            let mut leased_keys = std::mem::take(&mut self.leased_keys);
+            self.lost_keys.append(&mut leased_keys);
+            watch_result
+                .lock()
+                .await
+                .client_event(ClientEvent::LeaseLost { lease_id })
+                .await?;
+        }
+
+        if self.keeper.is_none() {
+            let lease_id = self.backend.lease_grant(self.lease_timeout).await?;
+            info!("Granted lease {}", lease_id);
+            self.keeper = Some(self.spawn_keeper(lease_id));
+        }
+        self.republish().await
+    }
+
+    /// Puts the keys of a lost lease back under the current one, unless somebody
+    /// else has created them in the meantime.
+    async fn republish(&mut self) -> Result<()> {
+        while let Some((key, value)) = self.lost_keys.pop_first() {
+            let op = Operation::CreateIfAbsent {
+                key: key.clone(),
+                value: value.clone(),
+                with_lease: true,
+            };
+            match self.kv_transaction(vec![op]).await {
+                Ok(outcome) if outcome.is_success() => info!("Re-published key {}", key),
+                Ok(_) => warn!("Key {} was created by someone else, not re-publishing", key),
+                Err(e) => {
+                    self.lost_keys.insert(key, value);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn monitor(
+        &mut self,
+        watch_result: Arc<Mutex<dyn WatchResult + Send + Sync>>,
+        kv_operator: Arc<Mutex<dyn KVOperator + Send + Sync>>,
+    ) -> Result<()> {
+        info!("Starting watching for changes on {:?}", &self.prefix);
+
+        // the operator is polled on every tick however busy the other branches are
+        let mut poll = tokio::time::interval(OPERATOR_POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            if *self.stop.borrow() {
+                // operations the operator still holds are applied before the lease goes
+                let flushed = match kv_operator.lock().await.ops().await {
+                    Ok(ops) => self.kv_operations(ops).await.map(|_| ()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = flushed {
+                    warn!("Unable to flush pending operations: {}", e);
+                }
+                return self.shutdown().await;
+            }
+
+            if self.watcher.is_none() {
+                if let Err(e) = self.go_online(&watch_result).await {
+                    self.recover(&watch_result, e).await?;
+                    continue;
+                }
+            }
+
+            if let Err(e) = self.check_lease(&watch_result).await {
+                self.recover(&watch_result, e).await?;
+                continue;
+            }
+
+            let watcher = match self.watcher.as_mut() {
+                Some(watcher) => watcher,
+                None => continue,
+            };
+            // only the receiving is raced, a re-read of the prefix runs to completion
+            let received = tokio::select! {
+                received = watcher.receive() => Some(received),
+                _ = poll.tick() => None,
+                (prefix, received) = receive_routed(&mut self.routes) => {
+                    let res = match self.routes.get_mut(&prefix) {
+                        Some(route) => route.watch.process(received).await,
+                        None => continue,
+                    };
+                    match res {
+                        Ok(Some(events)) if events.is_empty() => {}
+                        Ok(Some(events)) => self.deliver_routed(&prefix, events).await?,
+                        Ok(None) => {
+                            self.routes.remove(&prefix);
+                        }
+                        Err(e) => {
+                            self.recover(&watch_result, e).await?;
+                            continue;
+                        }
+                    }
+                    None
+                }
+                Some(submission) = self.submissions.recv() => {
+                    self.apply_submission(&watch_result, submission).await?;
+                    continue;
+                }
+                Some(command) = self.commands.recv() => {
+                    self.apply_command(command).await;
+                    continue;
+                }
+                _ = self.stop.changed() => continue,
+            };
+
+            if let (Some(received), Some(watcher)) = (received, self.watcher.as_mut()) {
+                let events = match watcher.process(received).await {
+                    Ok(Some(events)) => events,
+                    Ok(None) => return Ok(()),
+                    Err(e) => {
+                        self.recover(&watch_result, e).await?;
+                        continue;
+                    }
+                };
+                if !events.is_empty() {
+                    let revision = self.watcher.as_ref().map_or(0, PrefixWatch::revision);
+                    self.deliver(&watch_result, events, revision).await?;
+                }
+            }
+
+            let ops = kv_operator.lock().await.ops().await?;
+            self.apply_recovering(&watch_result, ops).await??;
+        }
+    }
+}
+
+/// Next response of any of the routed watches, pending while there are none.
+async fn receive_routed(
+    routes: &mut BTreeMap<String, RoutedWatch>,
+) -> (String, Result<Option<KVWatchResponse>>) {
+    if routes.is_empty() {
+        return std::future::pending().await;
+    }
+    let receives = routes.iter_mut().map(|(prefix, route)| {
+        Box::pin(async move { (prefix.clone(), route.watch.receive().await) })
+    });
+    select_all(receives).await.0
+}
+
+/// Splits events into consecutive batches of the same revision.
+fn by_revision(events: Vec<KVEvent>) -> Vec<Vec<WatchEvent>> {
+    let mut batches: Vec<Vec<WatchEvent>> = Vec::default();
+    for event in events.into_iter().map(WatchEvent::from) {
+        match batches.last_mut() {
+            Some(batch) if batch[0].mod_revision == event.mod_revision => batch.push(event),
+            _ => batches.push(vec![event]),
+        }
+    }
+    batches
+}
+
+/// Flattens a batch of a watch stream, passing on its error.
+fn events(batch: Result<Vec<WatchEvent>>) -> impl Stream<Item = Result<WatchEvent>> {
+    let events = match batch {
+        Ok(events) => events.into_iter().map(Ok).collect(),
+        Err(e) => vec![Err(e)],
+    };
+    stream::iter(events)
+}
+
+async fn resume_watch(
+    backend: &dyn KVBackend,
+    watch: &mut PrefixWatch,
+    policy: &ReconnectPolicy,
+    error: anyhow::Error,
+) -> Result<()> {
+    if !is_unavailable(&error) {
+        return Err(error);
+    }
+    warn!("Watch on {} failed: {}", watch.prefix(), error);
+    let mut attempt = 1;
+    loop {
+        if policy.exhausted(attempt) {
+            return Err(ConfigError::ReconnectFailed(attempt - 1).into());
+        }
+        tokio::time::sleep(policy.backoff(attempt)).await;
+        let res = match backend.reconnect().await {
+            Ok(()) => watch.resume().await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(()) => return Ok(()),
+            Err(e) if is_unavailable(&e) => {
+                warn!("Watch resume attempt {} failed: {}", attempt, e);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::ConfigError;
+    use crate::etcd_conf::{
+        ClientEvent, ConfClient, KVOperator, Operation, VarFetch, VarPathSpec, WatchEvent,
+        WatchResult,
+    };
+    use crate::kv_backend::{KVBackend, KVBytes, KVEventType, KVWatchOptions, MAX_TXN_OPS};
+    use crate::memory_backend::InMemoryBackend;
+    use crate::reconnect::ReconnectPolicy;
+    use crate::registry::ServiceInstance;
+    use crate::snapshot_file::SnapshotFile;
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use futures::{StreamExt, TryStreamExt};
+    use log::info;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder {
+        ops: Vec<Operation>,
+        watched: Vec<WatchEvent>,
+        batches: Vec<usize>,
+        events: Vec<ClientEvent>,
+    }
+
+    #[async_trait]
+    impl WatchResult for Recorder {
+        async fn notify(&mut self, res: Operation) -> Result<()> {
+            self.ops.push(res);
+            Ok(())
+        }
+
+        async fn watch_event(&mut self, event: WatchEvent) -> Result<()> {
+            self.watched.push(event.clone());
+            self.notify(event.into()).await
+        }
+
+        async fn watch_batch(&mut self, events: Vec<WatchEvent>) -> Result<()> {
+            self.batches.push(events.len());
+            for event in events {
+                self.watch_event(event).await?;
+            }
+            Ok(())
+        }
+
+        async fn client_event(&mut self, event: ClientEvent) -> Result<()> {
+            self.events.push(event);
+            Ok(())
+        }
+    }
+
+    struct Idle;
+
+    #[async_trait]
+    impl KVOperator for Idle {
+        async fn ops(&mut self) -> Result<Vec<Operation>> {
+            Ok(Vec::default())
+        }
+    }
+
+    fn fast_reconnect(max_attempts: Option<u32>) -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(20),
+            jitter: 0.0,
+            max_attempts,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_monitor() -> Result<()> {
+        let client = ConfClient::new(
+            vec!["10.0.0.1:2379".into()],
+            Some(("root".to_string(), "secret".to_string())),
+            "local/node".into(),
+            5,
+            10,
+        )
+        .await?;
+        check_monitor(client).await
+    }
+
+    #[tokio::test]
+    async fn test_monitor_in_memory() -> Result<()> {
+        let client =
+            ConfClient::with_backend(Arc::new(InMemoryBackend::new()), "local/node".into(), 5)
+                .await?;
+        check_monitor(client).await
+    }
+
+    async fn check_monitor(mut client: ConfClient) -> Result<()> {
+        client
+            .kv_operations(vec![
+                Operation::Set {
+                    key: "local/node".into(),
+                    value: "value".into(),
+                    with_lease: false,
+                },
+                Operation::Set {
+                    key: "local/node/leased".into(),
+                    value: "leased_value".into(),
+                    with_lease: true,
+                },
+            ])
+            .await?;
+
+        let res = client
+            .fetch_vars(&[VarPathSpec::SingleVar("local/node/leased".into())])
+            .await?;
+
+        assert_eq!(
+            res,
+            vec![VarFetch::Var(
+                "local/node/leased".into(),
+                "leased_value".into()
+            )]
+        );
+
+        let res = client
+            .fetch_vars(&[
+                VarPathSpec::Prefix("local/node".into()),
+                VarPathSpec::SingleVar("local/node/leased".into()),
+                VarPathSpec::SingleVar("local/node/missing".into()),
+            ])
+            .await?;
+
+        assert_eq!(
+            res,
+            vec![
+                VarFetch::Pairs(vec![
+                    ("local/node".into(), "value".into()),
+                    ("local/node/leased".into(), "leased_value".into()),
+                ]),
+                VarFetch::Var("local/node/leased".into(), "leased_value".into()),
+                VarFetch::NotFound("local/node/missing".into()),
+            ]
+        );
+        assert_eq!(
+            VarPathSpec::new_var("local/node", "leased")
+                .fetch(&client)
+                .await?
+                .into_pairs(),
+            vec![("local/node/leased".into(), "leased_value".into())]
+        );
+
+        #[derive(Default)]
+        struct Watcher {
+            counter: i32,
+            watch_result: Operation,
+        }
+
+        #[async_trait]
+        impl WatchResult for Watcher {
+            async fn notify(&mut self, res: Operation) -> Result<()> {
+                info!("Operation: {:?}", &res);
+                self.counter += 1;
+                self.watch_result = res;
+                Ok(())
+            }
+        }
+
+        struct Operator {
+            operation: Option<Operation>,
+        }
+
+        #[async_trait]
+        impl KVOperator for Operator {
+            async fn ops(&mut self) -> Result<Vec<Operation>> {
+                let op: Vec<_> = self.operation.take().into_iter().collect();
+                Ok(op)
+            }
+        }
+
+        // vec![VarPathSpec::SingleVar("local/node/leased".into())]
+        let w = Arc::new(Mutex::new(Watcher::default()));
+        let o = Arc::new(Mutex::new(Operator {
+            operation: Some(Operation::Set {
+                key: "local/node/leased".into(),
+                value: "new_leased".into(),
+                with_lease: true,
+            }),
+        }));
+
+        let t = tokio::spawn(async move {
+            match tokio::time::timeout(Duration::from_secs(5), client.monitor(w.clone(), o.clone()))
+                .await
+            {
+                Ok(res) => {
+                    panic!("Unexpected termination occurred: {:?}", res);
+                }
+                Err(_) => {
+                    assert_eq!(
+                        w.lock().await.watch_result,
+                        Operation::Set {
+                            key: "local/node/leased".into(),
+                            value: "new_leased".into(),
+                            with_lease: true,
+                        }
+                    );
+                    assert_eq!(w.lock().await.counter, 3);
+                }
+            }
+        });
+
+        tokio::join!(t).0?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kv_transaction() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        client
+            .kv_operations(vec![Operation::Set {
+                key: "local/node/old".into(),
+                value: "value".into(),
+                with_lease: false,
+            }])
+            .await?;
+
+        let mut watch = backend
+            .watch_prefix(b"local/node", KVWatchOptions::default())
+            .await?;
+        assert!(watch.message().await?.unwrap().created);
+
+        client.set_atomic_operations(true);
+        client
+            .kv_operations(vec![
+                Operation::DelPrefix {
+                    prefix: "local/node/old".into(),
+                },
+                Operation::Set {
+                    key: "local/node/a".into(),
+                    value: "a".into(),
+                    with_lease: false,
+                },
+                Operation::Set {
+                    key: "local/node/b".into(),
+                    value: "b".into(),
+                    with_lease: true,
+                },
+            ])
+            .await?;
+
+        let events = watch.message().await?.unwrap().events;
+        assert_eq!(events.len(), 3);
+        assert!(events
+            .iter()
+            .all(|e| e.kv.mod_revision == backend.revision()));
+
+        backend.lease_revoke(client.get_lease_id().unwrap()).await?;
+        let res = client
+            .kv_transaction(vec![
+                Operation::Set {
+                    key: "local/node/a".into(),
+                    value: "new_a".into(),
+                    with_lease: false,
+                },
+                Operation::Set {
+                    key: "local/node/c".into(),
+                    value: "c".into(),
+                    with_lease: true,
+                },
+            ])
+            .await;
+        assert!(res.is_err());
+
+        let res = client
+            .fetch_vars(&[VarPathSpec::Prefix("local/node".into())])
+            .await?;
+        assert_eq!(
             res,
+            vec![VarFetch::Pairs(vec![("local/node/a".into(), "a".into())])]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_conditional_operations() -> Result<()> {
+        let backend = Arc::new(InMemoryBackend::new());
+        let mut first = ConfClient::with_backend(backend.clone(), "local/node".into(), 5).await?;
+        let mut second = ConfClient::with_backend(backend.clone(), "local/node".into(), 5).await?;
+
+        let claim = Operation::CreateIfAbsent {
+            key: "local/node/owner".into(),
+            value: "first".into(),
+            with_lease: true,
+        };
+        assert!(first.kv_operations(vec![claim.clone()]).await?.is_success());
+
+        let claim = Operation::CreateIfAbsent {
+            key: "local/node/owner".into(),
+            value: "second".into(),
+            with_lease: true,
+        };
+        let res = second.kv_operations(vec![claim.clone()]).await?;
+        assert_eq!(res.failed, vec![claim]);
+
+        let owner = backend.get(b"local/node/owner").await?.unwrap();
+        let swap = Operation::SetIfModRevision {
+            key: "local/node/owner".into(),
+            mod_revision: owner.mod_revision,
+            value: "second".into(),
+            with_lease: true,
+        };
+        let stale = Operation::SetIfValue {
+            key: "local/node/owner".into(),
+            expected: "nobody".into(),
+            value: "second".into(),
+            with_lease: true,
+        };
+        let res = second
+            .kv_transaction(vec![
+                Operation::Set {
+                    key: "local/node/status".into(),
+                    value: "ready".into(),
+                    with_lease: false,
+                },
+                swap.clone(),
+                stale.clone(),
+            ])
+            .await?;
+        assert_eq!(res.failed, vec![stale]);
+        assert_eq!(backend.get(b"local/node/status").await?, None);
+
+        assert!(second
+            .kv_transaction(vec![swap.clone()])
+            .await?
+            .is_success());
+        let res = first.kv_transaction(vec![swap.clone()]).await?;
+        assert_eq!(res.failed, vec![swap]);
+
+        let release = Operation::DelIfValue {
+            key: "local/node/owner".into(),
+            expected: "first".into(),
+        };
+        let res = first.kv_operations(vec![release.clone()]).await?;
+        assert_eq!(res.failed, vec![release]);
+        assert_eq!(
+            backend.get(b"local/node/owner").await?.unwrap().value,
+            "second"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_monitor_reconnect() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        client.set_reconnect_policy(fast_reconnect(None));
+        let lease_id = client.get_lease_id();
+
+        let w = Arc::new(Mutex::new(Recorder::default()));
+        let watch_result = w.clone();
+        let t = tokio::spawn(async move {
+            let res = tokio::time::timeout(
+                Duration::from_secs(2),
+                client.monitor(watch_result, Arc::new(Mutex::new(Idle))),
+            )
+            .await;
+            assert!(res.is_err(), "Unexpected termination occurred: {:?}", res);
+            client
+        });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        backend.set_available(false);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        backend.set_available(true);
+        backend.put(b"local/node/key", b"value", None).await?;
+
+        let client = t.await?;
+        assert_eq!(client.get_lease_id(), lease_id);
+
+        let w = w.lock().await;
+        assert_eq!(
+            w.ops,
+            vec![Operation::Set {
+                key: "local/node/key".into(),
+                value: "value".into(),
+                with_lease: false,
+            }]
+        );
+        assert!(matches!(w.events[0], ClientEvent::Disconnected { .. }));
+        assert!(matches!(
+            w.events[1],
+            ClientEvent::Reconnecting { attempt: 1, .. }
+        ));
+        assert_eq!(w.events.last(), Some(&ClientEvent::Reconnected));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_monitor_reconnect_gives_up() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        client.set_reconnect_policy(fast_reconnect(Some(2)));
+        backend.set_available(false);
+
+        let w = Arc::new(Mutex::new(Recorder::default()));
+        let res = client
+            .monitor(w.clone(), Arc::new(Mutex::new(Idle)))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            res.downcast_ref::<ConfigError>(),
+            Some(ConfigError::ReconnectFailed(2))
+        ));
+        assert_eq!(w.lock().await.events.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_monitor_lease_lost() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 1).await?;
+        let lease_id = client.get_lease_id().unwrap();
+
+        let w = Arc::new(Mutex::new(Recorder::default()));
+        let watch_result = w.clone();
+        let t = tokio::spawn(async move {
+            let res = tokio::time::timeout(
+                Duration::from_secs(3),
+                client.monitor(watch_result, Arc::new(Mutex::new(Idle))),
+            )
+            .await;
+            assert!(res.is_err(), "Unexpected termination occurred: {:?}", res);
+            client
+        });
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(w.lock().await.events.is_empty());
+        backend.lease_revoke(lease_id).await?;
+
+        let client = t.await?;
+        assert_eq!(
+            w.lock().await.events,
+            vec![ClientEvent::LeaseLost { lease_id }]
+        );
+        let new_lease_id = client.get_lease_id().unwrap();
+        assert_ne!(new_lease_id, lease_id);
+        assert!(backend.lease_time_to_live(new_lease_id).await? > 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_republish_leased_keys() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 1).await?;
+        let lease_id = client.get_lease_id().unwrap();
+        client
+            .kv_operations(vec![
+                Operation::Set {
+                    key: "local/node/status".into(),
+                    value: "ready".into(),
+                    with_lease: true,
+                },
+                Operation::CreateIfAbsent {
+                    key: "local/node/owner".into(),
+                    value: "me".into(),
+                    with_lease: true,
+                },
+                Operation::Set {
+                    key: "local/node/gone".into(),
+                    value: "gone".into(),
+                    with_lease: true,
+                },
+                Operation::DelKey {
+                    key: "local/node/gone".into(),
+                },
+                Operation::Set {
+                    key: "local/node/plain".into(),
+                    value: "plain".into(),
+                    with_lease: false,
+                },
+            ])
+            .await?;
+        assert_eq!(
+            client.leased_keys().keys().collect::<Vec<_>>(),
+            vec!["local/node/owner", "local/node/status"]
+        );
+
+        let t = tokio::spawn(async move {
+            let res = tokio::time::timeout(
+                Duration::from_secs(2),
+                client.monitor(
+                    Arc::new(Mutex::new(Recorder::default())),
+                    Arc::new(Mutex::new(Idle)),
+                ),
+            )
+            .await;
+            assert!(res.is_err(), "Unexpected termination occurred: {:?}", res);
+            client
+        });
+
+        backend.lease_revoke(lease_id).await?;
+        assert_eq!(backend.get(b"local/node/status").await?, None);
+        backend.put(b"local/node/owner", b"other", None).await?;
+
+        let client = t.await?;
+        let new_lease_id = client.get_lease_id().unwrap();
+        let status = backend.get(b"local/node/status").await?.unwrap();
+        assert_eq!(status.value, "ready");
+        assert_eq!(status.lease, new_lease_id);
+        let owner = backend.get(b"local/node/owner").await?.unwrap();
+        assert_eq!((owner.value.as_str()?, owner.lease), ("other", 0));
+        assert_eq!(
+            client.leased_keys().keys().collect::<Vec<_>>(),
+            vec!["local/node/status"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_snapshot() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        backend.put(b"local/node/b", b"b", None).await?;
+        backend.put(b"local/node/a", b"a", None).await?;
+        backend.put(b"local/other", b"other", None).await?;
+        let client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+
+        let snapshot = client
+            .fetch_snapshot(&[
+                VarPathSpec::SingleVar("local/node/b".into()),
+                VarPathSpec::Prefix("local/node".into()),
+                VarPathSpec::SingleVar("local/other".into()),
+            ])
+            .await?;
+        assert_eq!(snapshot.revision, backend.revision());
+        assert_eq!(
+            snapshot.vars.into_iter().collect::<Vec<_>>(),
             vec![
-                ("local/node".into(), "value".into()),
-                ("local/node/leased".into(), "leased_value".into()),
-                ("local/node/leased".into(), "leased_value".into())
+                ("local/node/a".into(), "a".into()),
+                ("local/node/b".into(), "b".into()),
+                ("local/other".into(), "other".into()),
             ]
         );
 
-        #[derive(Default)]
-        struct Watcher {
-            counter: i32,
-            watch_result: Operation,
+        let missing = client
+            .fetch_snapshot(&[
+                VarPathSpec::Prefix("local/node".into()),
+                VarPathSpec::SingleVar("local/missing".into()),
+            ])
+            .await?;
+        assert_eq!(missing.vars.len(), 2);
+        assert_eq!(missing.missing, vec!["local/missing".to_string()]);
+
+        backend.put(b"local/node/c", b"c", None).await?;
+        let mut watch = client
+            .watch_from("local/node", snapshot.revision)
+            .await?
+            .boxed();
+        assert_eq!(watch.next().await.unwrap()?.key, "local/node/c");
+
+        let mut specs = vec![VarPathSpec::Prefix("local/node".into())];
+        for i in 0..MAX_TXN_OPS + 10 {
+            let key = format!("local/many/{:03}", i);
+            backend.put(key.as_bytes(), b"v", None).await?;
+            specs.push(VarPathSpec::SingleVar(key));
         }
+        let many = client.fetch_snapshot(&specs).await?;
+        assert_eq!(many.revision, backend.revision());
+        assert_eq!(many.vars.len(), MAX_TXN_OPS + 13);
+        assert!(many.missing.is_empty());
+        Ok(())
+    }
 
-        #[async_trait]
-        impl WatchResult for Watcher {
-            async fn notify(&mut self, res: Operation) -> Result<()> {
-                info!("Operation: {:?}", &res);
-                self.counter += 1;
-                self.watch_result = res;
-                Ok(())
+    #[tokio::test]
+    async fn test_scan() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        for i in 0..7 {
+            let key = format!("local/items/{}", i);
+            backend.put(key.as_bytes(), b"v", None).await?;
+        }
+        backend.put(b"local/itemsx", b"v", None).await?;
+        let client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+
+        let spec = VarPathSpec::new_prefix("local", "items/");
+        assert_eq!(client.count(&spec).await?, 7);
+
+        let mut stream = Box::pin(client.scan(&spec, 3));
+        let mut keys = Vec::default();
+        while let Some((key, value)) = stream.try_next().await? {
+            assert_eq!(value, "v");
+            keys.push(key);
+            if keys.len() == 1 {
+                // later pages are read at the revision of the first one
+                backend.put(b"local/items/9", b"v", None).await?;
             }
         }
+        assert_eq!(
+            keys,
+            (0..7)
+                .map(|i| format!("local/items/{}", i).into())
+                .collect::<Vec<KVBytes>>()
+        );
 
-        struct Operator {
-            operation: Option<Operation>,
+        let range = VarPathSpec::new_range("local/items/2", "local/items/5");
+        let keys: Vec<_> = client.scan_keys(&range, 2).try_collect().await?;
+        assert_eq!(
+            keys,
+            vec!["local/items/2", "local/items/3", "local/items/4"]
+        );
+        assert_eq!(client.count(&range).await?, 3);
+
+        let snapshot = client
+            .fetch_snapshot(&[VarPathSpec::new_range("local/items/8", "")])
+            .await?;
+        assert_eq!(
+            snapshot.vars.into_keys().collect::<Vec<_>>(),
+            vec!["local/items/9", "local/itemsx"]
+        );
+
+        // an empty prefix reads the whole key space
+        let everything = VarPathSpec::Prefix("".into());
+        assert_eq!(client.count(&everything).await?, 9);
+        let keys: Vec<_> = client.scan_keys(&everything, 4).try_collect().await?;
+        assert_eq!(keys.len(), 9);
+        let snapshot = client.fetch_snapshot(&[everything]).await?;
+        assert_eq!(snapshot.vars.len(), 9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_stream() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        client.set_reconnect_policy(fast_reconnect(None));
+
+        let all = client.watch("local/node").await?;
+        let mut only_b = Box::pin(client.watch("local/node/b").await?);
+        let collector = tokio::spawn(all.take(3).try_collect::<Vec<_>>());
+
+        client
+            .kv_operations(vec![
+                Operation::Set {
+                    key: "local/node/a".into(),
+                    value: "a".into(),
+                    with_lease: false,
+                },
+                Operation::Set {
+                    key: "local/node/b".into(),
+                    value: "b".into(),
+                    with_lease: false,
+                },
+            ])
+            .await?;
+        backend.set_available(false);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        backend.set_available(true);
+        client
+            .kv_operations(vec![Operation::DelKey {
+                key: "local/node/a".into(),
+            }])
+            .await?;
+
+        let events: Vec<_> = collector
+            .await??
+            .into_iter()
+            .map(|e| (e.event_type, e.key, e.value, e.prev_value))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (KVEventType::Put, "local/node/a".into(), "a".into(), None),
+                (KVEventType::Put, "local/node/b".into(), "b".into(), None),
+                (
+                    KVEventType::Delete,
+                    "local/node/a".into(),
+                    "".into(),
+                    Some("a".into())
+                ),
+            ]
+        );
+        let only_b = only_b.next().await.unwrap()?;
+        assert_eq!(
+            (only_b.key, only_b.value),
+            ("local/node/b".into(), "b".into())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_stream_error() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        client.set_reconnect_policy(fast_reconnect(Some(2)));
+
+        let mut watch = client.watch("local/node").await?.boxed();
+        backend.put(b"local/node/a", b"a", None).await?;
+        assert_eq!(watch.next().await.unwrap()?.key, "local/node/a");
+        backend.set_available(false);
+        let e = watch.next().await.unwrap().unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<ConfigError>(),
+            Some(ConfigError::ReconnectFailed(2))
+        ));
+        backend.set_available(true);
+        assert!(watch.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_event_metadata() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        let w = recorder.clone();
+        let t = tokio::spawn(async move { client.monitor(w, Arc::new(Mutex::new(Idle))).await });
+
+        let lease_id = backend.lease_grant(5).await?;
+        backend.put(b"local/node/a", b"a", None).await?;
+        backend.put(b"local/node/a", b"a1", Some(lease_id)).await?;
+        backend.delete(b"local/node/a").await?;
+        let watched = loop {
+            let watched = recorder.lock().await.watched.clone();
+            if watched.len() == 3 {
+                break watched;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        t.abort();
+
+        let (created, modified, deleted) = (&watched[0], &watched[1], &watched[2]);
+        assert!(created.is_create());
+        assert_eq!(created.prev_value, None);
+        assert_eq!(created.create_revision, created.mod_revision);
+
+        assert!(!modified.is_create());
+        assert_eq!(modified.prev_value, Some("a".into()));
+        assert_eq!(modified.value, "a1");
+        assert_eq!(modified.create_revision, created.mod_revision);
+        assert_eq!(modified.mod_revision, created.mod_revision + 1);
+        assert_eq!((modified.version, modified.lease), (2, lease_id));
+
+        assert_eq!(deleted.event_type, KVEventType::Delete);
+        assert_eq!(deleted.prev_value, Some("a1".into()));
+        assert_eq!(deleted.mod_revision, modified.mod_revision + 1);
+        assert_eq!(recorder.lock().await.ops.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch_batches() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        client.set_atomic_operations(true);
+        let batches = client.watch_batches("local/node").await?;
+        let collector = tokio::spawn(batches.take(2).try_collect::<Vec<_>>());
+        let writer = client.writer();
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        let w = recorder.clone();
+        let t = tokio::spawn(async move { client.monitor(w, Arc::new(Mutex::new(Idle))).await });
+
+        let set = |key: &str| Operation::Set {
+            key: key.into(),
+            value: "v".into(),
+            with_lease: false,
+        };
+        writer
+            .submit(vec![
+                set("local/node/a"),
+                set("local/node/b"),
+                set("local/node/c"),
+            ])
+            .await?;
+        writer
+            .submit(vec![Operation::DelPrefix {
+                prefix: "local/node/".into(),
+            }])
+            .await?;
+
+        let batches = tokio::time::timeout(Duration::from_millis(500), collector).await???;
+        for batch in &batches {
+            assert_eq!(batch.len(), 3);
+            assert!(batch
+                .iter()
+                .all(|e| e.mod_revision == batch[0].mod_revision));
         }
+        assert_eq!(batches[1][0].event_type, KVEventType::Delete);
 
-        #[async_trait]
-        impl KVOperator for Operator {
-            async fn ops(&mut self) -> Result<Vec<Operation>> {
-                let op: Vec<_> = self.operation.take().into_iter().collect();
-                Ok(op)
+        while recorder.lock().await.batches.len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        t.abort();
+        let recorder = recorder.lock().await;
+        assert_eq!(recorder.batches, vec![3, 3]);
+        assert_eq!(recorder.ops.len(), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_routes() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        backend.put(b"local/group/a", b"a", None).await?;
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        let node = Arc::new(Mutex::new(Recorder::default()));
+        let group = Arc::new(Mutex::new(Recorder::default()));
+        let global = Arc::new(Mutex::new(Recorder::default()));
+
+        let kvs = client.add_watch("local/group", group.clone()).await?;
+        assert_eq!(kvs.len(), 1);
+        let res = client.add_watch("local/node", global.clone()).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<ConfigError>(),
+            Some(ConfigError::DuplicateWatch(_))
+        ));
+
+        let router = client.router();
+        let w = node.clone();
+        let t = tokio::spawn(async move { client.monitor(w, Arc::new(Mutex::new(Idle))).await });
+        router.add("global", global.clone()).await?;
+
+        backend.put(b"local/node/a", b"a", None).await?;
+        backend.put(b"local/group/b", b"b", None).await?;
+        backend.put(b"global/c", b"c", None).await?;
+        let received = |recorder: Arc<Mutex<Recorder>>, count: usize| async move {
+            loop {
+                let ops = recorder.lock().await.ops.clone();
+                if ops.len() >= count {
+                    return ops;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let keys = |ops: Vec<Operation>| -> Vec<String> {
+            ops.iter()
+                .map(|op| match op {
+                    Operation::Set { key, .. } => key.to_string(),
+                    _ => String::default(),
+                })
+                .collect()
+        };
+        assert_eq!(keys(received(node.clone(), 1).await), vec!["local/node/a"]);
+        assert_eq!(
+            keys(received(group.clone(), 1).await),
+            vec!["local/group/b"]
+        );
+        assert_eq!(keys(received(global.clone(), 1).await), vec!["global/c"]);
+
+        assert!(router.remove("local/group").await?);
+        assert!(!router.remove("local/group").await?);
+        backend.put(b"local/group/d", b"d", None).await?;
+        backend.put(b"local/node/e", b"e", None).await?;
+        received(node.clone(), 2).await;
+        assert_eq!(group.lock().await.ops.len(), 1);
+
+        t.abort();
+        assert!(matches!(
+            router
+                .remove("global")
+                .await
+                .unwrap_err()
+                .downcast_ref::<ConfigError>(),
+            Some(ConfigError::ClientClosed)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_writer() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        client.set_atomic_operations(true);
+        let writer = client.writer();
+        let t = tokio::spawn(async move {
+            client
+                .monitor(
+                    Arc::new(Mutex::new(Recorder::default())),
+                    Arc::new(Mutex::new(Idle)),
+                )
+                .await
+        });
+
+        let set = |value: &str| Operation::Set {
+            key: "local/node/key".into(),
+            value: value.into(),
+            with_lease: false,
+        };
+        let claim = Operation::CreateIfAbsent {
+            key: "local/node/key".into(),
+            value: "claimed".into(),
+            with_lease: false,
+        };
+        let outcome = tokio::time::timeout(
+            Duration::from_millis(500),
+            writer.submit(vec![set("value")]),
+        )
+        .await??;
+        assert!(outcome.is_success());
+        assert_eq!(
+            backend.get(b"local/node/key").await?.unwrap().value,
+            "value"
+        );
+
+        let outcome = writer.clone().submit(vec![claim.clone()]).await?;
+        assert_eq!(outcome.failed, vec![claim]);
+
+        let res = writer.submit(vec![set("a"), set("b")]).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<ConfigError>(),
+            Some(ConfigError::DuplicateTxnKey(_))
+        ));
+
+        t.abort();
+        assert!(t.await.unwrap_err().is_cancelled());
+        let res = writer.submit(vec![set("late")]).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<ConfigError>(),
+            Some(ConfigError::ClientClosed)
+        ));
+        Ok(())
+    }
+
+    struct Pending(Vec<Operation>);
+
+    #[async_trait]
+    impl KVOperator for Pending {
+        async fn ops(&mut self) -> Result<Vec<Operation>> {
+            Ok(std::mem::take(&mut self.0))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_operator_polled_under_writes() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        let writer = client.writer();
+        let operator = Arc::new(Mutex::new(Pending(Vec::default())));
+        let o = operator.clone();
+        let t = tokio::spawn(async move {
+            client
+                .monitor(Arc::new(Mutex::new(Recorder::default())), o)
+                .await
+        });
+        let w = tokio::spawn(async move {
+            for i in 0.. {
+                let op = Operation::Set {
+                    key: "local/node/written".into(),
+                    value: i.to_string().into(),
+                    with_lease: false,
+                };
+                writer.submit(vec![op]).await?;
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
+            Ok::<_, anyhow::Error>(())
+        });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        operator.lock().await.0.push(Operation::Set {
+            key: "local/node/operated".into(),
+            value: "v".into(),
+            with_lease: false,
+        });
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(backend.get(b"local/node/operated").await?.is_some());
+        w.abort();
+        t.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        client
+            .kv_operations(vec![Operation::Set {
+                key: "local/node/registration".into(),
+                value: "up".into(),
+                with_lease: true,
+            }])
+            .await?;
+        let lease_id = client.get_lease_id().unwrap();
+        let handle = client.shutdown_handle();
+
+        let t = tokio::spawn(async move {
+            client
+                .monitor(
+                    Arc::new(Mutex::new(Recorder::default())),
+                    Arc::new(Mutex::new(Pending(vec![Operation::Set {
+                        key: "local/node/state".into(),
+                        value: "stopped".into(),
+                        with_lease: false,
+                    }]))),
+                )
+                .await?;
+            Ok::<_, anyhow::Error>(client)
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        handle.shutdown();
+
+        let mut client = tokio::time::timeout(Duration::from_millis(500), t).await???;
+        assert_eq!(client.get_lease_id(), None);
+        assert!(client.leased_keys().is_empty());
+        assert_eq!(backend.get(b"local/node/registration").await?, None);
+        assert!(backend.lease_time_to_live(lease_id).await? <= 0);
+        assert_eq!(
+            backend.get(b"local/node/state").await?.unwrap().value,
+            "stopped"
+        );
+
+        // the handle stays fired, and shutting down twice is harmless
+        client
+            .monitor(
+                Arc::new(Mutex::new(Recorder::default())),
+                Arc::new(Mutex::new(Idle)),
+            )
+            .await?;
+        assert_eq!(client.get_lease_id(), None);
+
+        // without a lease a leased write fails instead of storing a permanent key
+        let leased = Operation::Set {
+            key: "local/node/registration".into(),
+            value: "up".into(),
+            with_lease: true,
+        };
+        for atomic in [false, true] {
+            client.set_atomic_operations(atomic);
+            let res = client.kv_operations(vec![leased.clone()]).await;
+            assert!(matches!(
+                res.unwrap_err().downcast_ref::<ConfigError>(),
+                Some(ConfigError::NoLease)
+            ));
         }
+        assert!(client.leased_keys().is_empty());
+        assert_eq!(backend.get(b"local/node/registration").await?, None);
+        Ok(())
+    }
 
-        // vec![VarPathSpec::SingleVar("local/node/leased".into())]
-        let w = Arc::new(Mutex::new(Watcher::default()));
-        let o = Arc::new(Mutex::new(Operator {
-            operation: Some(Operation::Set {
-                key: "local/node/leased".into(),
-                value: "new_leased".into(),
+    #[tokio::test]
+    async fn test_shutdown_with_dead_watch() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        client
+            .add_watch("local/group", Arc::new(Mutex::new(Recorder::default())))
+            .await?;
+        client
+            .kv_operations(vec![Operation::Set {
+                key: "local/node/registration".into(),
+                value: "up".into(),
                 with_lease: true,
-            }),
-        }));
+            }])
+            .await?;
+        let lease_id = client.get_lease_id().unwrap();
+
+        backend.disconnect_watchers();
+        assert!(client.shutdown().await.is_err());
+        assert_eq!(backend.get(b"local/node/registration").await?, None);
+        assert!(backend.lease_time_to_live(lease_id).await? <= 0);
+        assert_eq!(client.watched_prefixes(), vec!["local/node"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        backend.put(b"local/node/a", b"a", None).await?;
+        let mut client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        // a change between the watch start and the load is not lost
+        backend.put(b"local/node/b", b"b", None).await?;
+        let cache = client.enable_cache().await?;
+        assert_eq!(
+            cache.list(b"local/node/"),
+            vec![("local/node/a".into(), "a".into())]
+        );
 
+        let handle = client.shutdown_handle();
         let t = tokio::spawn(async move {
-            match tokio::time::timeout(Duration::from_secs(5), client.monitor(w.clone(), o.clone()))
+            client
+                .monitor(
+                    Arc::new(Mutex::new(Recorder::default())),
+                    Arc::new(Mutex::new(Idle)),
+                )
                 .await
-            {
-                Ok(res) => {
-                    panic!("Unexpected termination occurred: {:?}", res);
-                }
-                Err(_) => {
-                    assert_eq!(
-                        w.lock().await.watch_result,
-                        Operation::Set {
-                            key: "local/node/leased".into(),
-                            value: "new_leased".into(),
-                            with_lease: true,
-                        }
-                    );
-                    assert_eq!(w.lock().await.counter, 3);
-                }
-            }
         });
+        backend.delete(b"local/node/a").await?;
+        backend.put(b"local/other", b"other", None).await?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
 
-        tokio::join!(t).0?;
+        assert_eq!(cache.get(b"local/node/a"), None);
+        assert_eq!(cache.get(b"local/node/b"), Some("b".into()));
+        assert_eq!(cache.get(b"local/other"), None);
+        assert_eq!(cache.revision(), backend.revision() - 1);
+        handle.shutdown();
+        t.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_offline_leased_writes() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("conf-{}.json", uuid::Uuid::new_v4()));
+        let backend = InMemoryBackend::new();
+        let file = SnapshotFile::new(&path);
+        let start = || {
+            ConfClient::with_backend_or_snapshot(
+                Arc::new(backend.clone()),
+                "local/node".into(),
+                5,
+                file.clone(),
+            )
+        };
+        drop(start().await?);
+        backend.set_available(false);
+        let mut client = start().await?;
+        assert!(client.is_offline());
+
+        // reachable again, but `monitor` has not granted a lease yet
+        backend.set_available(true);
+        let res = client
+            .kv_operations(vec![Operation::Set {
+                key: "local/node/leased".into(),
+                value: "v".into(),
+                with_lease: true,
+            }])
+            .await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<ConfigError>(),
+            Some(ConfigError::NoLease)
+        ));
+        let instance = ServiceInstance {
+            id: "a".into(),
+            address: "10.0.0.1:80".into(),
+            metadata: Default::default(),
+        };
+        let res = client.register("svc", &instance).await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<ConfigError>(),
+            Some(ConfigError::NoLease)
+        ));
+        assert!(client.leased_keys().is_empty());
+        assert_eq!(backend.get_prefix(b"").await?.kvs, vec![]);
+        assert!(client.discovery("svc").await?.instances().is_empty());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_offline_start() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("conf-{}.json", uuid::Uuid::new_v4()));
+        let backend = InMemoryBackend::new();
+        backend.put(b"local/node/a", b"1", None).await?;
+        backend.put(b"local/node/b", b"2", None).await?;
+
+        let client = ConfClient::with_backend_or_snapshot(
+            Arc::new(backend.clone()),
+            "local/node".into(),
+            5,
+            SnapshotFile::new(&path),
+        )
+        .await?;
+        assert!(!client.is_offline());
+        drop(client);
+
+        backend.put(b"local/node/a", b"10", None).await?;
+        backend.delete(b"local/node/b").await?;
+        backend.put(b"local/node/c", b"3", None).await?;
+        backend.put(b"local/group/a", b"4", None).await?;
+        backend.set_available(false);
+
+        let mut client = ConfClient::with_backend_or_snapshot(
+            Arc::new(backend.clone()),
+            "local/node".into(),
+            5,
+            SnapshotFile::new(&path),
+        )
+        .await?;
+        assert!(client.is_offline());
+        assert_eq!(client.get_lease_id(), None);
+        let cache = client.cache().unwrap().clone();
+        assert_eq!(cache.get(b"local/node/b"), Some("2".into()));
+        let group = Arc::new(Mutex::new(Recorder::default()));
+        client.add_route("local/group", group.clone()).await?;
+        assert!(client
+            .add_route("local/group", group.clone())
+            .await
+            .is_err());
 
+        client.set_reconnect_policy(fast_reconnect(None));
+        let handle = client.shutdown_handle();
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        let watch_result = recorder.clone();
+        let t = tokio::spawn(async move {
+            client
+                .monitor(watch_result, Arc::new(Mutex::new(Idle)))
+                .await?;
+            Ok::<_, anyhow::Error>(client)
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        backend.set_available(true);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        handle.shutdown();
+        let client = t.await??;
+        assert!(!client.is_offline());
+
+        assert_eq!(
+            cache.list(b"local/node/"),
+            vec![
+                ("local/node/a".into(), "10".into()),
+                ("local/node/c".into(), "3".into())
+            ]
+        );
+        let w = recorder.lock().await;
+        assert!(w.events.contains(&ClientEvent::Reconnected));
+        assert_eq!(
+            w.ops,
+            vec![
+                Operation::Set {
+                    key: "local/node/a".into(),
+                    value: "10".into(),
+                    with_lease: false
+                },
+                Operation::Set {
+                    key: "local/node/c".into(),
+                    value: "3".into(),
+                    with_lease: false
+                },
+                Operation::DelKey {
+                    key: "local/node/b".into()
+                },
+            ]
+        );
+        let group = group.lock().await;
+        assert_eq!(group.batches, vec![1]);
+        assert_eq!(group.watched[0].key, KVBytes::from("local/group/a"));
+        let saved = SnapshotFile::new(&path).load("local/node").await?.unwrap();
+        assert_eq!(saved, *cache.snapshot());
+        tokio::fs::remove_file(&path).await?;
         Ok(())
     }
 }
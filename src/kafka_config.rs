@@ -33,7 +33,7 @@ pub fn load_kafka_config(path: &str) -> Result<ClientConfig> {
         }
         let key_value: Vec<_> = cur_line.split('=').collect();
         let key = key_value
-            .get(0)
+            .first()
             .ok_or_else(|| ConfigLoadErrors::KeySplitError(cur_line.clone()))?;
         let value = key_value
             .get(1)
@@ -0,0 +1,820 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::borrow::{Borrow, Cow};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use etcd_client::*;
+use futures::{stream, Stream, TryStreamExt};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tonic::Code;
+
+use crate::errors::ConfigError;
+use crate::tls::TlsConfig;
+
+/// Operations etcd accepts in one branch of a transaction by default, see its
+/// `--max-txn-ops` flag.
+pub const MAX_TXN_OPS: usize = 128;
+
+/// Raw key or value bytes, etcd does not require them to be UTF-8.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct KVBytes(Vec<u8>);
+
+impl KVBytes {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn as_str(&self) -> Result<&str> {
+        Ok(std::str::from_utf8(&self.0)?)
+    }
+
+    pub fn to_string_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn into_string(self) -> Result<String> {
+        Ok(String::from_utf8(self.0)?)
+    }
+}
+
+impl Deref for KVBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for KVBytes {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for KVBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        KVBytes(bytes)
+    }
+}
+
+impl From<&[u8]> for KVBytes {
+    fn from(bytes: &[u8]) -> Self {
+        KVBytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for KVBytes {
+    fn from(s: String) -> Self {
+        KVBytes(s.into_bytes())
+    }
+}
+
+impl From<&str> for KVBytes {
+    fn from(s: &str) -> Self {
+        KVBytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<&String> for KVBytes {
+    fn from(s: &String) -> Self {
+        KVBytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<KVBytes> for Vec<u8> {
+    fn from(bytes: KVBytes) -> Self {
+        bytes.0
+    }
+}
+
+impl PartialEq<str> for KVBytes {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for KVBytes {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<String> for KVBytes {
+    fn eq(&self, other: &String) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<[u8]> for KVBytes {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.0 == other
+    }
+}
+
+impl fmt::Debug for KVBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match std::str::from_utf8(&self.0) {
+            Ok(s) => write!(f, "{:?}", s),
+            Err(_) => write!(f, "{:?}", &self.0),
+        }
+    }
+}
+
+impl fmt::Display for KVBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_lossy())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct KVEntry {
+    pub key: KVBytes,
+    pub value: KVBytes,
+    pub create_revision: i64,
+    pub mod_revision: i64,
+    pub version: i64,
+    pub lease: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KVEventType {
+    Put,
+    Delete,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KVEvent {
+    pub event_type: KVEventType,
+    pub kv: KVEntry,
+    /// State before the event, only with `KVWatchOptions::prev_kv`.
+    pub prev_kv: Option<KVEntry>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct KVWatchResponse {
+    pub created: bool,
+    pub canceled: bool,
+    /// Set when the requested start revision has already been compacted.
+    pub compact_revision: i64,
+    pub events: Vec<KVEvent>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct KVWatchOptions {
+    /// First revision to deliver events for, zero means the current one.
+    pub start_revision: i64,
+    /// Reports the previous state of changed keys as `KVEvent::prev_kv`.
+    pub prev_kv: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KVGetResponse {
+    pub kvs: Vec<KVEntry>,
+    /// Current store revision, not the one requested in `KVGetOptions`.
+    pub revision: i64,
+    /// Number of keys in the range, regardless of the limit.
+    pub count: i64,
+    /// Set when the limit left keys of the range out.
+    pub more: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct KVGetOptions {
+    /// Maximum number of entries, zero means no limit.
+    pub limit: i64,
+    /// Revision to read at, zero means the current one.
+    pub revision: i64,
+    /// Leaves the values out.
+    pub keys_only: bool,
+    /// Only sets `count`.
+    pub count_only: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KVTxnOp {
+    Put {
+        key: KVBytes,
+        value: KVBytes,
+        lease_id: Option<i64>,
+    },
+    Delete {
+        key: KVBytes,
+    },
+    DeletePrefix {
+        prefix: KVBytes,
+    },
+    Get {
+        key: KVBytes,
+    },
+    /// Keys in `[from, to)`, see `KVBackend::get_range`.
+    GetRange {
+        from: KVBytes,
+        to: KVBytes,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KVCompareTarget {
+    Value(KVBytes),
+    ModRevision(i64),
+    CreateRevision(i64),
+}
+
+/// Equality check of a key attribute; a missing key has zero revisions and
+/// matches no value.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KVCompare {
+    pub key: KVBytes,
+    pub target: KVCompareTarget,
+}
+
+impl KVCompare {
+    pub fn matches(&self, entry: Option<&KVEntry>) -> bool {
+        match &self.target {
+            KVCompareTarget::Value(value) => entry.is_some_and(|e| &e.value == value),
+            KVCompareTarget::ModRevision(rev) => entry.map_or(0, |e| e.mod_revision) == *rev,
+            KVCompareTarget::CreateRevision(rev) => entry.map_or(0, |e| e.create_revision) == *rev,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KVTxn {
+    pub compares: Vec<KVCompare>,
+    pub success: Vec<KVTxnOp>,
+    pub failure: Vec<KVTxnOp>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KVTxnResponse {
+    pub succeeded: bool,
+    pub revision: i64,
+    /// Results of the `Get` operations of the executed branch, in order.
+    pub gets: Vec<Option<KVEntry>>,
+    /// Results of the `GetRange` operations of the executed branch, in order.
+    pub ranges: Vec<Vec<KVEntry>>,
+}
+
+/// Ownership of an election, `rev` is the create revision of the leader key.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KVLeaderKey {
+    pub name: String,
+    pub key: String,
+    pub rev: i64,
+    pub lease: i64,
+}
+
+#[async_trait]
+pub trait KVWatchStream: Send {
+    async fn message(&mut self) -> Result<Option<KVWatchResponse>>;
+    async fn cancel(&mut self) -> Result<()>;
+}
+
+/// Leader key of an election, delivered every time the leader or its value changes.
+#[async_trait]
+pub trait KVLeaderStream: Send {
+    async fn message(&mut self) -> Result<Option<KVEntry>>;
+}
+
+/// Storage operations `ConfClient` relies on. Implemented by `EtcdBackend` for
+/// real clusters and by `InMemoryBackend` for tests.
+#[async_trait]
+pub trait KVBackend: Send + Sync {
+    async fn get(&self, key: &[u8]) -> Result<Option<KVEntry>>;
+    async fn get_prefix(&self, prefix: &[u8]) -> Result<KVGetResponse>;
+    /// Keys in `[from, to)` in key order, an empty `from` or `to` means no lower
+    /// or upper bound.
+    async fn get_range(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        options: KVGetOptions,
+    ) -> Result<KVGetResponse>;
+    async fn put(&self, key: &[u8], value: &[u8], lease_id: Option<i64>) -> Result<()>;
+    async fn delete(&self, key: &[u8]) -> Result<()>;
+    async fn delete_prefix(&self, prefix: &[u8]) -> Result<()>;
+    /// Executes `success` operations in a single revision when all compares hold,
+    /// `failure` operations otherwise.
+    async fn txn(&self, txn: KVTxn) -> Result<KVTxnResponse>;
+    async fn watch_prefix(
+        &self,
+        prefix: &[u8],
+        options: KVWatchOptions,
+    ) -> Result<Box<dyn KVWatchStream>>;
+    async fn lease_grant(&self, ttl: i64) -> Result<i64>;
+    /// Refreshes the lease and returns its TTL in seconds, not positive when the
+    /// lease no longer exists.
+    async fn lease_keep_alive(&self, lease_id: i64) -> Result<i64>;
+    /// Remaining TTL of the lease in seconds, not positive once it has expired.
+    async fn lease_time_to_live(&self, lease_id: i64) -> Result<i64>;
+    async fn lease_revoke(&self, lease_id: i64) -> Result<()>;
+    /// Re-establishes the connection after `is_unavailable` errors.
+    async fn reconnect(&self) -> Result<()>;
+    /// Waits until `value` is elected under the lease, the candidate key stays
+    /// in the store as long as the lease does.
+    async fn campaign(&self, name: &str, value: &str, lease_id: i64) -> Result<KVLeaderKey>;
+    async fn proclaim(&self, leader: &KVLeaderKey, value: &str) -> Result<()>;
+    async fn leader(&self, name: &str) -> Result<Option<KVEntry>>;
+    async fn observe(&self, name: &str) -> Result<Box<dyn KVLeaderStream>>;
+    async fn resign(&self, leader: &KVLeaderKey) -> Result<()>;
+}
+
+/// End of the range holding the keys that start with `prefix`, empty when no
+/// upper bound is needed.
+pub fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            break;
+        }
+    }
+    end
+}
+
+/// Reads `[from, to)` in pages of `options.limit` keys, continuing after the last
+/// key of each page. All pages are read at the revision of the first one, so the
+/// stream fails if that revision is compacted before the end.
+pub fn paginate(
+    backend: Arc<dyn KVBackend>,
+    from: Vec<u8>,
+    to: Vec<u8>,
+    options: KVGetOptions,
+) -> impl Stream<Item = Result<KVEntry>> {
+    stream::try_unfold(Some((from, options)), move |page| {
+        let backend = backend.clone();
+        let to = to.clone();
+        async move {
+            let (from, mut options) = match page {
+                Some(page) => page,
+                None => return Ok(None),
+            };
+            let resp = backend.get_range(&from, &to, options.clone()).await?;
+            if options.revision == 0 {
+                options.revision = resp.revision;
+            }
+            let next = match resp.kvs.last() {
+                Some(last) if resp.more => {
+                    let mut from = last.key.to_vec();
+                    from.push(0);
+                    Some((from, options))
+                }
+                _ => None,
+            };
+            Ok::<_, anyhow::Error>(Some((stream::iter(resp.kvs.into_iter().map(Ok)), next)))
+        }
+    })
+    .try_flatten()
+}
+
+/// Tells connectivity problems, which are worth a reconnect, from request errors.
+pub fn is_unavailable(e: &anyhow::Error) -> bool {
+    if let Some(ConfigError::BackendUnavailable) = e.downcast_ref::<ConfigError>() {
+        return true;
+    }
+    match e.downcast_ref::<Error>() {
+        Some(Error::TransportError(_))
+        | Some(Error::IoError(_))
+        | Some(Error::WatchError(_))
+        | Some(Error::LeaseKeepAliveError(_)) => true,
+        Some(Error::GRpcStatus(status)) => matches!(
+            status.code(),
+            Code::Unavailable | Code::DeadlineExceeded | Code::Cancelled
+        ),
+        _ => false,
+    }
+}
+
+pub struct EtcdBackend {
+    /// `None` until the first successful connection of a `disconnected` backend.
+    client: RwLock<Option<Client>>,
+    endpoints: Vec<String>,
+    options: ConnectOptions,
+    next_endpoint: AtomicUsize,
+    keepers: tokio::sync::Mutex<HashMap<i64, (LeaseKeeper, LeaseKeepAliveStream)>>,
+}
+
+impl EtcdBackend {
+    pub async fn connect(
+        uris: Vec<String>,
+        credentials: Option<(String, String)>,
+        tls: Option<TlsConfig>,
+        connect_timeout: u64,
+    ) -> Result<EtcdBackend> {
+        info!("Connecting to {:?} etcd server", &uris);
+        let backend = EtcdBackend::disconnected(uris, credentials, tls, connect_timeout);
+        let client = Client::connect(&backend.endpoints, Some(backend.options.clone())).await?;
+        *backend.client.write().unwrap() = Some(client);
+        Ok(backend)
+    }
+
+    /// Backend failing with `ConfigError::BackendUnavailable` until `reconnect`
+    /// succeeds, for starting without a reachable cluster.
+    pub fn disconnected(
+        uris: Vec<String>,
+        credentials: Option<(String, String)>,
+        tls: Option<TlsConfig>,
+        connect_timeout: u64,
+    ) -> EtcdBackend {
+        let options = {
+            let mut opts = ConnectOptions::new();
+            if let Some((user, password)) = credentials {
+                opts = opts.with_user(user, password);
+            }
+            if let Some(tls) = tls {
+                opts = opts.with_tls(tls.options());
+            }
+            opts.with_timeout(Duration::from_secs(connect_timeout))
+        };
+        EtcdBackend {
+            client: RwLock::new(None),
+            endpoints: uris,
+            options,
+            next_endpoint: AtomicUsize::new(0),
+            keepers: Default::default(),
+        }
+    }
+
+    fn client(&self) -> Result<Client> {
+        let client = self.client.read().unwrap().clone();
+        client.ok_or_else(|| ConfigError::BackendUnavailable.into())
+    }
+}
+
+impl TryFrom<&LeaderKey> for KVLeaderKey {
+    type Error = anyhow::Error;
+
+    fn try_from(key: &LeaderKey) -> Result<Self> {
+        Ok(KVLeaderKey {
+            name: key.name_str()?.to_string(),
+            key: key.key_str()?.to_string(),
+            rev: key.rev(),
+            lease: key.lease(),
+        })
+    }
+}
+
+impl From<&KVLeaderKey> for LeaderKey {
+    fn from(key: &KVLeaderKey) -> Self {
+        LeaderKey::new()
+            .with_name(key.name.as_str())
+            .with_key(key.key.as_str())
+            .with_rev(key.rev)
+            .with_lease(key.lease)
+    }
+}
+
+impl From<&KeyValue> for KVEntry {
+    fn from(kv: &KeyValue) -> Self {
+        KVEntry {
+            key: kv.key().into(),
+            value: kv.value().into(),
+            create_revision: kv.create_revision(),
+            mod_revision: kv.mod_revision(),
+            version: kv.version(),
+            lease: kv.lease(),
+        }
+    }
+}
+
+#[async_trait]
+impl KVBackend for EtcdBackend {
+    async fn get(&self, key: &[u8]) -> Result<Option<KVEntry>> {
+        let resp = self.client()?.get(key, None).await?;
+        Ok(resp.kvs().first().map(KVEntry::from))
+    }
+
+    async fn get_prefix(&self, prefix: &[u8]) -> Result<KVGetResponse> {
+        let resp = self
+            .client()?
+            .get(prefix, Some(GetOptions::new().with_prefix()))
+            .await?;
+        Ok(KVGetResponse {
+            kvs: resp.kvs().iter().map(KVEntry::from).collect(),
+            revision: resp.header().map(|h| h.revision()).unwrap_or_default(),
+            count: resp.count(),
+            more: resp.more(),
+        })
+    }
+
+    async fn get_range(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        options: KVGetOptions,
+    ) -> Result<KVGetResponse> {
+        let (from, to) = etcd_range(from, to);
+        let mut opts = GetOptions::new()
+            .with_range(to)
+            .with_limit(options.limit)
+            .with_revision(options.revision);
+        if options.keys_only {
+            opts = opts.with_keys_only();
+        }
+        if options.count_only {
+            opts = opts.with_count_only();
+        }
+        let resp = self.client()?.get(from, Some(opts)).await?;
+        Ok(KVGetResponse {
+            kvs: resp.kvs().iter().map(KVEntry::from).collect(),
+            revision: resp.header().map(|h| h.revision()).unwrap_or_default(),
+            count: resp.count(),
+            more: resp.more(),
+        })
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8], lease_id: Option<i64>) -> Result<()> {
+        let mut opts = PutOptions::new();
+        if let Some(lease_id) = lease_id {
+            opts = opts.with_lease(lease_id);
+        }
+        self.client()?.put(key, value, Some(opts)).await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.client()?.delete(key, None).await?;
+        Ok(())
+    }
+
+    async fn delete_prefix(&self, prefix: &[u8]) -> Result<()> {
+        self.client()?
+            .delete(prefix, Some(DeleteOptions::new().with_prefix()))
+            .await?;
+        Ok(())
+    }
+
+    async fn txn(&self, txn: KVTxn) -> Result<KVTxnResponse> {
+        let compares: Vec<_> = txn
+            .compares
+            .into_iter()
+            .map(|c| match c.target {
+                KVCompareTarget::Value(value) => Compare::value(c.key, CompareOp::Equal, value),
+                KVCompareTarget::ModRevision(rev) => {
+                    Compare::mod_revision(c.key, CompareOp::Equal, rev)
+                }
+                KVCompareTarget::CreateRevision(rev) => {
+                    Compare::create_revision(c.key, CompareOp::Equal, rev)
+                }
+            })
+            .collect();
+        let is_range = |ops: &[KVTxnOp]| -> Vec<bool> {
+            ops.iter()
+                .map(|op| matches!(op, KVTxnOp::GetRange { .. }))
+                .collect()
+        };
+        let ranged = (is_range(&txn.success), is_range(&txn.failure));
+        let txn = Txn::new()
+            .when(compares)
+            .and_then(etcd_txn_ops(txn.success))
+            .or_else(etcd_txn_ops(txn.failure));
+        let resp = self.client()?.txn(txn).await?;
+
+        let ranged = if resp.succeeded() { ranged.0 } else { ranged.1 };
+        let mut gets = Vec::default();
+        let mut ranges = Vec::default();
+        for (range, op_resp) in ranged.into_iter().zip(resp.op_responses()) {
+            if let TxnOpResponse::Get(get) = op_resp {
+                if range {
+                    ranges.push(get.kvs().iter().map(KVEntry::from).collect());
+                } else {
+                    gets.push(get.kvs().first().map(KVEntry::from));
+                }
+            }
+        }
+        Ok(KVTxnResponse {
+            succeeded: resp.succeeded(),
+            revision: resp.header().map(|h| h.revision()).unwrap_or_default(),
+            gets,
+            ranges,
+        })
+    }
+
+    async fn watch_prefix(
+        &self,
+        prefix: &[u8],
+        options: KVWatchOptions,
+    ) -> Result<Box<dyn KVWatchStream>> {
+        let mut opts = WatchOptions::new().with_prefix();
+        if options.start_revision > 0 {
+            opts = opts.with_start_revision(options.start_revision);
+        }
+        if options.prev_kv {
+            opts = opts.with_prev_key();
+        }
+        let (watcher, stream) = self.client()?.watch(prefix, Some(opts)).await?;
+        Ok(Box::new(EtcdWatchStream { watcher, stream }))
+    }
+
+    async fn lease_grant(&self, ttl: i64) -> Result<i64> {
+        let lease = self.client()?.lease_grant(ttl, None).await?;
+        Ok(lease.id())
+    }
+
+    async fn lease_keep_alive(&self, lease_id: i64) -> Result<i64> {
+        // the keep-alive stream is reused between calls and dropped on any error,
+        // or when the call is canceled, e.g. by the keeper timeout
+        let mut keepers = self.keepers.lock().await;
+        let (mut keeper, mut stream) = match keepers.remove(&lease_id) {
+            Some(keeper) => keeper,
+            None => self.client()?.lease_keep_alive(lease_id).await?,
+        };
+        keeper.keep_alive().await?;
+        let ttl = match stream.message().await? {
+            Some(resp) => resp.ttl(),
+            None => {
+                return Err(Error::LeaseKeepAliveError("keep-alive stream closed".into()).into())
+            }
+        };
+        if ttl > 0 {
+            keepers.insert(lease_id, (keeper, stream));
+        }
+        Ok(ttl)
+    }
+
+    async fn lease_time_to_live(&self, lease_id: i64) -> Result<i64> {
+        let resp = self.client()?.lease_time_to_live(lease_id, None).await?;
+        Ok(resp.ttl())
+    }
+
+    async fn lease_revoke(&self, lease_id: i64) -> Result<()> {
+        self.keepers.lock().await.remove(&lease_id);
+        self.client()?.lease_revoke(lease_id).await?;
+        Ok(())
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        if self.endpoints.is_empty() {
+            return Err(ConfigError::BackendUnavailable.into());
+        }
+        // every attempt starts at the next endpoint, so a dead member is skipped
+        let idx = self.next_endpoint.fetch_add(1, Ordering::Relaxed) % self.endpoints.len();
+        let (before, from) = self.endpoints.split_at(idx);
+        let endpoints: Vec<_> = from.iter().chain(before).collect();
+        info!("Reconnecting to {:?} etcd server", &endpoints);
+        let mut client = Client::connect(&endpoints, Some(self.options.clone())).await?;
+        client.status().await?;
+        *self.client.write().unwrap() = Some(client);
+        self.keepers.lock().await.clear();
+        Ok(())
+    }
+
+    async fn campaign(&self, name: &str, value: &str, lease_id: i64) -> Result<KVLeaderKey> {
+        let resp = self.client()?.campaign(name, value, lease_id).await?;
+        match resp.leader() {
+            Some(leader) => leader.try_into(),
+            None => Err(ConfigError::CampaignAborted(name.to_string()).into()),
+        }
+    }
+
+    async fn proclaim(&self, leader: &KVLeaderKey, value: &str) -> Result<()> {
+        let opts = ProclaimOptions::new().with_leader(leader.into());
+        self.client()?.proclaim(value, Some(opts)).await?;
+        Ok(())
+    }
+
+    async fn leader(&self, name: &str) -> Result<Option<KVEntry>> {
+        match self.client()?.leader(name).await {
+            Ok(resp) => Ok(resp.kv().map(KVEntry::from)),
+            // etcd answers `election: no leader` with a failed precondition
+            Err(Error::GRpcStatus(status)) if status.code() == Code::FailedPrecondition => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn observe(&self, name: &str) -> Result<Box<dyn KVLeaderStream>> {
+        let stream = self.client()?.observe(name).await?;
+        Ok(Box::new(EtcdLeaderStream { stream }))
+    }
+
+    async fn resign(&self, leader: &KVLeaderKey) -> Result<()> {
+        let opts = ResignOptions::new().with_leader(leader.into());
+        self.client()?.resign(Some(opts)).await?;
+        Ok(())
+    }
+}
+
+fn etcd_txn_ops(ops: Vec<KVTxnOp>) -> Vec<TxnOp> {
+    ops.into_iter()
+        .map(|op| match op {
+            KVTxnOp::Put {
+                key,
+                value,
+                lease_id,
+            } => {
+                let mut opts = PutOptions::new();
+                if let Some(lease_id) = lease_id {
+                    opts = opts.with_lease(lease_id);
+                }
+                TxnOp::put(key, value, Some(opts))
+            }
+            KVTxnOp::Delete { key } => TxnOp::delete(key, None),
+            KVTxnOp::DeletePrefix { prefix } => {
+                TxnOp::delete(prefix, Some(DeleteOptions::new().with_prefix()))
+            }
+            KVTxnOp::Get { key } => TxnOp::get(key, None),
+            KVTxnOp::GetRange { from, to } => {
+                let (from, to) = etcd_range(&from, &to);
+                TxnOp::get(from, Some(GetOptions::new().with_range(to)))
+            }
+        })
+        .collect()
+}
+
+/// Key and range end of `[from, to)` for etcd, which rejects an empty key and
+/// reads every key from the key on when the range end is "\0".
+fn etcd_range(from: &[u8], to: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let bound = |key: &[u8]| {
+        if key.is_empty() {
+            vec![0]
+        } else {
+            key.to_vec()
+        }
+    };
+    (bound(from), bound(to))
+}
+
+struct EtcdWatchStream {
+    watcher: Watcher,
+    stream: WatchStream,
+}
+
+#[async_trait]
+impl KVWatchStream for EtcdWatchStream {
+    async fn message(&mut self) -> Result<Option<KVWatchResponse>> {
+        match self.stream.message().await? {
+            Some(resp) => {
+                let mut events = Vec::default();
+                for event in resp.events() {
+                    if let Some(kv) = event.kv() {
+                        events.push(KVEvent {
+                            event_type: match event.event_type() {
+                                EventType::Put => KVEventType::Put,
+                                EventType::Delete => KVEventType::Delete,
+                            },
+                            kv: kv.into(),
+                            prev_kv: event.prev_kv().map(KVEntry::from),
+                        });
+                    }
+                }
+                Ok(Some(KVWatchResponse {
+                    created: resp.created(),
+                    canceled: resp.canceled(),
+                    compact_revision: resp.compact_revision(),
+                    events,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn cancel(&mut self) -> Result<()> {
+        self.watcher.cancel().await?;
+        Ok(())
+    }
+}
+
+struct EtcdLeaderStream {
+    stream: ObserveStream,
+}
+
+#[async_trait]
+impl KVLeaderStream for EtcdLeaderStream {
+    async fn message(&mut self) -> Result<Option<KVEntry>> {
+        loop {
+            match self.stream.message().await? {
+                Some(resp) => {
+                    if let Some(kv) = resp.kv() {
+                        return Ok(Some(kv.into()));
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
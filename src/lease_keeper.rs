@@ -0,0 +1,212 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use log::{info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::kv_backend::KVBackend;
+
+pub const MIN_KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LeaseState {
+    Alive { ttl: i64 },
+    Lost,
+}
+
+/// Keeps a lease alive from a background task, refreshing it three times per
+/// TTL. The lease is reported lost when the backend no longer knows it, or when
+/// its deadline passes without a successful refresh. The task stops on drop.
+pub struct LeaseKeeper {
+    lease_id: i64,
+    state: watch::Receiver<LeaseState>,
+    interval: watch::Sender<Duration>,
+    task: JoinHandle<()>,
+}
+
+impl LeaseKeeper {
+    pub fn spawn(backend: Arc<dyn KVBackend>, lease_id: i64, lease_timeout: i64) -> LeaseKeeper {
+        let interval = keep_alive_interval(lease_timeout);
+        LeaseKeeper::spawn_with_interval(backend, lease_id, lease_timeout, interval)
+    }
+
+    /// Refreshes the lease every `interval` instead of three times per TTL,
+    /// but not more often than `MIN_KEEP_ALIVE_INTERVAL`.
+    pub fn spawn_with_interval(
+        backend: Arc<dyn KVBackend>,
+        lease_id: i64,
+        lease_timeout: i64,
+        interval: Duration,
+    ) -> LeaseKeeper {
+        let (sender, state) = watch::channel(LeaseState::Alive { ttl: lease_timeout });
+        let (interval, period) = watch::channel(interval.max(MIN_KEEP_ALIVE_INTERVAL));
+        let task = tokio::spawn(keep_alive(backend, lease_id, lease_timeout, period, sender));
+        LeaseKeeper {
+            lease_id,
+            state,
+            interval,
+            task,
+        }
+    }
+
+    pub fn lease_id(&self) -> i64 {
+        self.lease_id
+    }
+
+    pub fn state(&self) -> LeaseState {
+        *self.state.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<LeaseState> {
+        self.state.clone()
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.state() == LeaseState::Lost
+    }
+
+    /// Changes the refresh interval of the running keeper; the pending wait
+    /// restarts with the new interval. Clamped to `MIN_KEEP_ALIVE_INTERVAL`.
+    pub fn set_interval(&self, interval: Duration) {
+        self.interval
+            .send_replace(interval.max(MIN_KEEP_ALIVE_INTERVAL));
+    }
+
+    /// Resolves once the lease is lost. Cancel safe.
+    pub async fn lost(&mut self) {
+        while !self.is_lost() {
+            if self.state.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for LeaseKeeper {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Resolves once the lease behind the receiver is lost or its keeper is dropped.
+pub async fn lease_lost(mut lease: watch::Receiver<LeaseState>) {
+    while *lease.borrow() != LeaseState::Lost {
+        if lease.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+pub fn keep_alive_interval(lease_timeout: i64) -> Duration {
+    (Duration::from_secs(lease_timeout.max(0) as u64) / 3).max(MIN_KEEP_ALIVE_INTERVAL)
+}
+
+async fn keep_alive(
+    backend: Arc<dyn KVBackend>,
+    lease_id: i64,
+    lease_timeout: i64,
+    mut interval: watch::Receiver<Duration>,
+    sender: watch::Sender<LeaseState>,
+) {
+    let mut deadline = Instant::now() + Duration::from_secs(lease_timeout.max(0) as u64);
+    loop {
+        let period = *interval.borrow_and_update();
+        tokio::select! {
+            _ = tokio::time::sleep(period) => {}
+            Ok(()) = interval.changed() => continue,
+        }
+        // a refresh hanging on a half-open connection fails at the lease deadline
+        let refresh = tokio::time::timeout_at(deadline, backend.lease_keep_alive(lease_id));
+        let res = match refresh.await {
+            Ok(res) => res,
+            Err(_) => Err(anyhow!("keep-alive timed out")),
+        };
+        match res {
+            Ok(ttl) if ttl > 0 => {
+                deadline = Instant::now() + Duration::from_secs(ttl as u64);
+                sender.send_replace(LeaseState::Alive { ttl });
+            }
+            Ok(_) => {
+                warn!("Lease {} has expired", lease_id);
+                break;
+            }
+            Err(e) if Instant::now() >= deadline => {
+                warn!("Lease {} is lost, last keep-alive error: {}", lease_id, e);
+                break;
+            }
+            Err(e) => {
+                info!("Lease {} keep-alive failed: {}, retrying", lease_id, e);
+            }
+        }
+    }
+    sender.send_replace(LeaseState::Lost);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv_backend::KVBackend;
+    use crate::lease_keeper::{LeaseKeeper, LeaseState};
+    use crate::memory_backend::InMemoryBackend;
+    use anyhow::Result;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_lease_keeper() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let lease_id = backend.lease_grant(1).await?;
+        let mut keeper = LeaseKeeper::spawn(Arc::new(backend.clone()), lease_id, 1);
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(keeper.state(), LeaseState::Alive { ttl: 1 });
+        assert!(backend.lease_time_to_live(lease_id).await? > 0);
+
+        backend.lease_revoke(lease_id).await?;
+        tokio::time::timeout(Duration::from_secs(1), keeper.lost()).await?;
+        assert!(keeper.is_lost());
+
+        let lease_id = backend.lease_grant(1).await?;
+        let mut keeper = LeaseKeeper::spawn(Arc::new(backend.clone()), lease_id, 1);
+        backend.set_available(false);
+        tokio::time::timeout(Duration::from_secs(2), keeper.lost()).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_interval() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let lease_id = backend.lease_grant(1).await?;
+        let keeper = LeaseKeeper::spawn_with_interval(
+            Arc::new(backend.clone()),
+            lease_id,
+            1,
+            Duration::from_secs(5),
+        );
+        let state = keeper.subscribe();
+
+        keeper.set_interval(Duration::from_millis(200));
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+        assert_eq!(keeper.state(), LeaseState::Alive { ttl: 1 });
+        assert!(state.has_changed()?);
+        assert!(backend.lease_time_to_live(lease_id).await? > 0);
+        Ok(())
+    }
+}
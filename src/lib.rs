@@ -1,3 +1,5 @@
+pub mod conf_builder;
+pub mod election;
 pub mod errors;
 pub mod etcd_conf;
 /**
@@ -17,4 +19,15 @@ pub mod etcd_conf;
  */
 pub mod hocon_config;
 pub mod kafka_config;
+pub mod kv_backend;
+pub mod lease_keeper;
+pub mod lock;
+pub mod memory_backend;
 pub mod mqtt;
+pub mod prefix_cache;
+pub mod prefix_watch;
+pub mod reconnect;
+pub mod registry;
+pub mod snapshot_file;
+pub mod tls;
+pub mod typed;
@@ -0,0 +1,299 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{info, warn};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::errors::ConfigError;
+use crate::kv_backend::{KVBackend, KVEntry, KVEventType, KVWatchOptions};
+use crate::lease_keeper::{lease_lost, LeaseState};
+
+/// Counting semaphore over keys attached to the lease of the `ConfClient` it was
+/// created from: every waiter creates a key under `name/`, and the `permits` keys
+/// with the lowest create revisions hold a permit. Keys of crashed holders vanish
+/// with their lease. All participants have to agree on `permits`.
+pub struct KVSemaphore {
+    backend: Arc<dyn KVBackend>,
+    name: String,
+    permits: usize,
+    lease_id: i64,
+    lease: watch::Receiver<LeaseState>,
+}
+
+/// Held permit, released on drop or with `release`.
+pub struct KVLockGuard {
+    backend: Arc<dyn KVBackend>,
+    name: String,
+    key: String,
+    released: bool,
+}
+
+/// `KVSemaphore` with a single permit.
+pub struct KVMutex {
+    semaphore: KVSemaphore,
+}
+
+impl KVSemaphore {
+    pub fn new(
+        backend: Arc<dyn KVBackend>,
+        name: &str,
+        permits: usize,
+        lease_id: i64,
+        lease: watch::Receiver<LeaseState>,
+    ) -> KVSemaphore {
+        KVSemaphore {
+            backend,
+            name: name.to_string(),
+            permits,
+            lease_id,
+            lease,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Waits for a permit. Fails with `ConfigError::LockAborted` when the lease is
+    /// lost first.
+    pub async fn acquire(&self) -> Result<KVLockGuard> {
+        let guard = self.enqueue().await?;
+        tokio::select! {
+            res = self.wait(&guard.key) => res?,
+            _ = lease_lost(self.lease.clone()) => {
+                return Err(ConfigError::LockAborted(self.name.clone()).into())
+            }
+        }
+        info!("Acquired {} as {}", &self.name, &guard.key);
+        Ok(guard)
+    }
+
+    /// Waits for a permit for at most `timeout`, `None` once it has passed.
+    pub async fn acquire_timeout(&self, timeout: Duration) -> Result<Option<KVLockGuard>> {
+        match tokio::time::timeout(timeout, self.acquire()).await {
+            Ok(guard) => guard.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Takes a permit only if one is free right away.
+    pub async fn try_acquire(&self) -> Result<Option<KVLockGuard>> {
+        let guard = self.enqueue().await?;
+        if self.holds(&guard.key).await? {
+            Ok(Some(guard))
+        } else {
+            guard.release().await?;
+            Ok(None)
+        }
+    }
+
+    async fn enqueue(&self) -> Result<KVLockGuard> {
+        let key = format!("{}/{:x}-{}", &self.name, self.lease_id, Uuid::new_v4());
+        self.backend
+            .put(key.as_bytes(), b"", Some(self.lease_id))
+            .await?;
+        Ok(KVLockGuard {
+            backend: self.backend.clone(),
+            name: self.name.clone(),
+            key,
+            released: false,
+        })
+    }
+
+    async fn holds(&self, key: &str) -> Result<bool> {
+        let prefix = format!("{}/", &self.name);
+        let snapshot = self.backend.get_prefix(prefix.as_bytes()).await?;
+        self.rank(key, &snapshot.kvs)
+    }
+
+    fn rank(&self, key: &str, kvs: &[KVEntry]) -> Result<bool> {
+        let mut waiters: Vec<_> = kvs.iter().collect();
+        waiters.sort_by_key(|kv| kv.create_revision);
+        match waiters.iter().position(|kv| kv.key == key) {
+            Some(rank) => Ok(rank < self.permits),
+            None => Err(ConfigError::LockAborted(self.name.clone()).into()),
+        }
+    }
+
+    async fn wait(&self, key: &str) -> Result<()> {
+        let prefix = format!("{}/", &self.name);
+        loop {
+            let snapshot = self.backend.get_prefix(prefix.as_bytes()).await?;
+            if self.rank(key, &snapshot.kvs)? {
+                return Ok(());
+            }
+            // only a released permit can let us in
+            let mut watch = self
+                .backend
+                .watch_prefix(
+                    prefix.as_bytes(),
+                    KVWatchOptions {
+                        start_revision: snapshot.revision + 1,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            while let Some(resp) = watch.message().await? {
+                if resp.canceled
+                    || resp
+                        .events
+                        .iter()
+                        .any(|e| e.event_type == KVEventType::Delete)
+                {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl KVLockGuard {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub async fn release(mut self) -> Result<()> {
+        self.released = true;
+        self.backend.delete(self.key.as_bytes()).await
+    }
+}
+
+impl Drop for KVLockGuard {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let backend = self.backend.clone();
+            let key = std::mem::take(&mut self.key);
+            let name = self.name.clone();
+            runtime.spawn(async move {
+                if let Err(e) = backend.delete(key.as_bytes()).await {
+                    warn!("Unable to release {}: {}", name, e);
+                }
+            });
+        }
+    }
+}
+
+impl KVMutex {
+    pub fn new(
+        backend: Arc<dyn KVBackend>,
+        name: &str,
+        lease_id: i64,
+        lease: watch::Receiver<LeaseState>,
+    ) -> KVMutex {
+        KVMutex {
+            semaphore: KVSemaphore::new(backend, name, 1, lease_id, lease),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.semaphore.name()
+    }
+
+    pub async fn lock(&self) -> Result<KVLockGuard> {
+        self.semaphore.acquire().await
+    }
+
+    pub async fn lock_timeout(&self, timeout: Duration) -> Result<Option<KVLockGuard>> {
+        self.semaphore.acquire_timeout(timeout).await
+    }
+
+    pub async fn try_lock(&self) -> Result<Option<KVLockGuard>> {
+        self.semaphore.try_acquire().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::etcd_conf::ConfClient;
+    use crate::kv_backend::KVBackend;
+    use crate::memory_backend::InMemoryBackend;
+    use anyhow::Result;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_mutex() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let first =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        let second =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+
+        let guard = first.lock("local/lock").await?;
+        let mutex = second.mutex("local/lock")?;
+        assert!(mutex.try_lock().await?.is_none());
+        assert!(mutex
+            .lock_timeout(Duration::from_millis(100))
+            .await?
+            .is_none());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(backend.get_prefix(b"local/lock/").await?.kvs.len(), 1);
+
+        let waiter = tokio::spawn(async move { mutex.lock().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiter.is_finished());
+        drop(guard);
+        let guard = tokio::time::timeout(Duration::from_secs(1), waiter).await???;
+
+        let mutex = first.mutex("local/lock")?;
+        let waiter = tokio::spawn(async move { mutex.lock().await });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiter.is_finished());
+        // a crashed holder is released with its lease
+        backend.lease_revoke(second.get_lease_id().unwrap()).await?;
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await???
+            .release()
+            .await?;
+        drop(guard);
+        assert!(backend.get_prefix(b"local/lock/").await?.kvs.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_semaphore() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let client =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+
+        let semaphore = client.semaphore("local/semaphore", 2)?;
+        let first = semaphore.acquire().await?;
+        let _second = semaphore.try_acquire().await?.unwrap();
+        assert!(semaphore.try_acquire().await?.is_none());
+
+        let semaphore = Arc::new(semaphore);
+        let waiter = {
+            let semaphore = semaphore.clone();
+            tokio::spawn(async move { semaphore.acquire().await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiter.is_finished());
+        first.release().await?;
+        let _third = tokio::time::timeout(Duration::from_secs(1), waiter).await???;
+        assert!(semaphore
+            .acquire_timeout(Duration::from_millis(100))
+            .await?
+            .is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,881 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::errors::ConfigError;
+use crate::kv_backend::{
+    prefix_end, KVBackend, KVBytes, KVCompare, KVCompareTarget, KVEntry, KVEvent, KVEventType,
+    KVGetOptions, KVGetResponse, KVLeaderKey, KVLeaderStream, KVTxn, KVTxnOp, KVTxnResponse,
+    KVWatchOptions, KVWatchResponse, KVWatchStream, MAX_TXN_OPS,
+};
+
+const LEASE_REAPER_INTERVAL_MS: u64 = 100;
+
+/// Process-local `KVBackend` with etcd-like semantics: every mutation bumps the store
+/// revision, leased keys are removed when the lease expires and watchers receive
+/// the resulting events. Clones share the same store.
+#[derive(Clone, Default)]
+pub struct InMemoryBackend {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    revision: i64,
+    kvs: BTreeMap<KVBytes, KVEntry>,
+    leases: HashMap<i64, Lease>,
+    last_lease_id: i64,
+    watchers: HashMap<u64, WatchSlot>,
+    last_watch_id: u64,
+    history: Vec<KVEvent>,
+    /// Store contents as of `compact_revision`, `history` applies on top of it.
+    compacted: BTreeMap<KVBytes, KVEntry>,
+    compact_revision: i64,
+    unavailable: bool,
+    reaper_started: bool,
+}
+
+struct Lease {
+    ttl: i64,
+    deadline: Instant,
+    keys: BTreeSet<KVBytes>,
+}
+
+struct WatchSlot {
+    prefix: KVBytes,
+    prev_kv: bool,
+    sender: mpsc::UnboundedSender<KVWatchResponse>,
+}
+
+type TxnReads = (Vec<Option<KVEntry>>, Vec<Vec<KVEntry>>);
+
+impl State {
+    /// Returns the results of the `Get` and `GetRange` operations.
+    fn apply(&mut self, ops: &[KVTxnOp]) -> Result<TxnReads> {
+        self.validate(ops)?;
+
+        let revision = self.revision + 1;
+        let mut events = Vec::default();
+        let mut gets = Vec::default();
+        let mut ranges = Vec::default();
+        for op in ops {
+            match op {
+                KVTxnOp::Put {
+                    key,
+                    value,
+                    lease_id,
+                } => events.push(self.put_at(key, value, lease_id.unwrap_or(0), revision)),
+                KVTxnOp::Delete { key } => {
+                    events.append(&mut self.delete_at(vec![key.clone()], revision))
+                }
+                KVTxnOp::DeletePrefix { prefix } => {
+                    let keys = self.prefix_keys(prefix);
+                    events.append(&mut self.delete_at(keys, revision))
+                }
+                KVTxnOp::Get { key } => gets.push(self.kvs.get(key).cloned()),
+                KVTxnOp::GetRange { from, to } => {
+                    ranges.push(self.range(from, to).cloned().collect())
+                }
+            }
+        }
+
+        if !events.is_empty() {
+            self.revision = revision;
+            self.notify(events);
+        }
+        Ok((gets, ranges))
+    }
+
+    fn validate(&self, ops: &[KVTxnOp]) -> Result<()> {
+        let mut put_keys = BTreeSet::default();
+        for op in ops {
+            if let KVTxnOp::Put { key, lease_id, .. } = op {
+                let lease = lease_id.unwrap_or(0);
+                if lease != 0 && !self.leases.contains_key(&lease) {
+                    return Err(ConfigError::LeaseNotFound(lease).into());
+                }
+                if !put_keys.insert(key.as_bytes()) {
+                    return Err(ConfigError::DuplicateTxnKey(key.to_string()).into());
+                }
+            }
+        }
+        // like etcd, a key cannot be both put and deleted within one revision
+        for op in ops {
+            let conflict = match op {
+                KVTxnOp::Delete { key } => put_keys.get(key.as_bytes()).copied(),
+                KVTxnOp::DeletePrefix { prefix } => {
+                    put_keys.iter().find(|k| k.starts_with(prefix)).copied()
+                }
+                KVTxnOp::Put { .. } | KVTxnOp::Get { .. } | KVTxnOp::GetRange { .. } => None,
+            };
+            if let Some(key) = conflict {
+                return Err(ConfigError::DuplicateTxnKey(
+                    String::from_utf8_lossy(key).into_owned(),
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    fn put_at(&mut self, key: &KVBytes, value: &KVBytes, lease: i64, revision: i64) -> KVEvent {
+        let prev_kv = self.kvs.get(key).cloned();
+        let entry = match prev_kv.clone() {
+            Some(prev) => {
+                if prev.lease != lease {
+                    self.detach_lease(prev.lease, key);
+                }
+                KVEntry {
+                    key: key.clone(),
+                    value: value.clone(),
+                    create_revision: prev.create_revision,
+                    mod_revision: revision,
+                    version: prev.version + 1,
+                    lease,
+                }
+            }
+            None => KVEntry {
+                key: key.clone(),
+                value: value.clone(),
+                create_revision: revision,
+                mod_revision: revision,
+                version: 1,
+                lease,
+            },
+        };
+        if let Some(l) = self.leases.get_mut(&lease) {
+            l.keys.insert(key.clone());
+        }
+        self.kvs.insert(key.clone(), entry.clone());
+        KVEvent {
+            event_type: KVEventType::Put,
+            kv: entry,
+            prev_kv,
+        }
+    }
+
+    fn delete_at(&mut self, keys: Vec<KVBytes>, revision: i64) -> Vec<KVEvent> {
+        let mut events = Vec::default();
+        for key in keys {
+            if let Some(prev) = self.kvs.remove(&key) {
+                self.detach_lease(prev.lease, &key);
+                events.push(KVEvent {
+                    event_type: KVEventType::Delete,
+                    kv: KVEntry {
+                        key,
+                        mod_revision: revision,
+                        ..Default::default()
+                    },
+                    prev_kv: Some(prev),
+                });
+            }
+        }
+        events
+    }
+
+    fn prefix_keys(&self, prefix: &[u8]) -> Vec<KVBytes> {
+        self.kvs
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    fn range<'a>(&'a self, from: &'a [u8], to: &'a [u8]) -> impl Iterator<Item = &'a KVEntry> {
+        self.kvs
+            .range::<[u8], _>((Bound::Included(from), Bound::Unbounded))
+            .take_while(move |(k, _)| to.is_empty() || k.as_bytes() < to)
+            .map(|(_, kv)| kv)
+    }
+
+    /// Entries of `[from, to)` as of `revision`, rebuilt from the history.
+    fn range_at(&self, from: &[u8], to: &[u8], revision: i64) -> Result<Vec<KVEntry>> {
+        if revision == 0 || revision == self.revision {
+            return Ok(self.range(from, to).cloned().collect());
+        }
+        if revision < self.compact_revision || revision > self.revision {
+            return Err(ConfigError::RevisionUnavailable(revision).into());
+        }
+        let mut kvs = self.compacted.clone();
+        replay(
+            &mut kvs,
+            self.history
+                .iter()
+                .filter(|e| e.kv.mod_revision <= revision),
+        );
+        Ok(kvs
+            .into_values()
+            .filter(|kv| kv.key.as_bytes() >= from && (to.is_empty() || kv.key.as_bytes() < to))
+            .collect())
+    }
+
+    fn detach_lease(&mut self, lease_id: i64, key: &[u8]) {
+        if let Some(l) = self.leases.get_mut(&lease_id) {
+            l.keys.remove(key);
+        }
+    }
+
+    fn revoke(&mut self, lease_id: i64) -> bool {
+        match self.leases.remove(&lease_id) {
+            Some(lease) => {
+                let revision = self.revision + 1;
+                let events = self.delete_at(lease.keys.into_iter().collect(), revision);
+                if !events.is_empty() {
+                    self.revision = revision;
+                    self.notify(events);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expire_leases(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .leases
+            .iter()
+            .filter(|(_, l)| l.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for lease_id in expired {
+            self.revoke(lease_id);
+        }
+    }
+
+    fn notify(&mut self, events: Vec<KVEvent>) {
+        self.history.extend(events.iter().cloned());
+        self.watchers.retain(|_, w| {
+            let matched = watched(events.iter(), &w.prefix, w.prev_kv);
+            matched.is_empty()
+                || w.sender
+                    .send(KVWatchResponse {
+                        events: matched,
+                        ..Default::default()
+                    })
+                    .is_ok()
+        });
+    }
+}
+
+/// Events of keys under `prefix`, without the previous state unless `prev_kv`.
+fn watched<'a>(
+    events: impl Iterator<Item = &'a KVEvent>,
+    prefix: &[u8],
+    prev_kv: bool,
+) -> Vec<KVEvent> {
+    events
+        .filter(|e| e.kv.key.starts_with(prefix))
+        .map(|e| KVEvent {
+            prev_kv: e.prev_kv.clone().filter(|_| prev_kv),
+            ..e.clone()
+        })
+        .collect()
+}
+
+fn replay<'a>(kvs: &mut BTreeMap<KVBytes, KVEntry>, events: impl Iterator<Item = &'a KVEvent>) {
+    for event in events {
+        match event.event_type {
+            KVEventType::Put => kvs.insert(event.kv.key.clone(), event.kv.clone()),
+            KVEventType::Delete => kvs.remove(&event.kv.key),
+        };
+    }
+}
+
+impl State {
+    /// Candidate of the election with the lowest create revision, as etcd elects.
+    fn election_leader(&self, name: &str) -> Option<&KVEntry> {
+        self.prefix_keys(format!("{}/", name).as_bytes())
+            .iter()
+            .filter_map(|k| self.kvs.get(k))
+            .min_by_key(|kv| kv.create_revision)
+    }
+}
+
+impl InMemoryBackend {
+    pub fn new() -> InMemoryBackend {
+        InMemoryBackend::default()
+    }
+
+    pub fn revision(&self) -> i64 {
+        self.state().revision
+    }
+
+    /// Discards the event history up to and including `revision`; watches
+    /// starting at or before it are canceled with `compact_revision` set.
+    pub fn compact(&self, revision: i64) {
+        let mut state = self.state();
+        let state = &mut *state;
+        state.compact_revision = revision;
+        replay(
+            &mut state.compacted,
+            state
+                .history
+                .iter()
+                .filter(|e| e.kv.mod_revision <= revision),
+        );
+        state.history.retain(|e| e.kv.mod_revision > revision);
+    }
+
+    /// Closes all watch streams as a lost connection would.
+    pub fn disconnect_watchers(&self) {
+        self.state().watchers.clear();
+    }
+
+    /// Simulates an outage: while unavailable every request fails with
+    /// `ConfigError::BackendUnavailable` and watch streams are closed. Leases keep
+    /// expiring as they would on the server.
+    pub fn set_available(&self, available: bool) {
+        let mut state = self.state();
+        state.unavailable = !available;
+        if !available {
+            state.watchers.clear();
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn connected(&self) -> Result<MutexGuard<'_, State>> {
+        let state = self.state();
+        if state.unavailable {
+            return Err(ConfigError::BackendUnavailable.into());
+        }
+        Ok(state)
+    }
+
+    fn spawn_lease_reaper(&self) {
+        let state: Weak<Mutex<State>> = Arc::downgrade(&self.state);
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_millis(LEASE_REAPER_INTERVAL_MS));
+            loop {
+                interval.tick().await;
+                match state.upgrade() {
+                    Some(state) => state.lock().unwrap().expire_leases(Instant::now()),
+                    None => break,
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl KVBackend for InMemoryBackend {
+    async fn get(&self, key: &[u8]) -> Result<Option<KVEntry>> {
+        Ok(self.connected()?.kvs.get(key).cloned())
+    }
+
+    async fn get_prefix(&self, prefix: &[u8]) -> Result<KVGetResponse> {
+        self.get_range(prefix, &prefix_end(prefix), KVGetOptions::default())
+            .await
+    }
+
+    async fn get_range(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        options: KVGetOptions,
+    ) -> Result<KVGetResponse> {
+        let state = self.connected()?;
+        let mut kvs = state.range_at(from, to, options.revision)?;
+        let count = kvs.len() as i64;
+        if options.limit > 0 {
+            kvs.truncate(options.limit as usize);
+        }
+        let more = (kvs.len() as i64) < count;
+        if options.count_only {
+            kvs.clear();
+        } else if options.keys_only {
+            for kv in kvs.iter_mut() {
+                kv.value = KVBytes::default();
+            }
+        }
+        Ok(KVGetResponse {
+            kvs,
+            revision: state.revision,
+            count,
+            more,
+        })
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8], lease_id: Option<i64>) -> Result<()> {
+        self.connected()?.apply(&[KVTxnOp::Put {
+            key: key.into(),
+            value: value.into(),
+            lease_id,
+        }])?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.connected()?
+            .apply(&[KVTxnOp::Delete { key: key.into() }])?;
+        Ok(())
+    }
+
+    async fn delete_prefix(&self, prefix: &[u8]) -> Result<()> {
+        self.connected()?.apply(&[KVTxnOp::DeletePrefix {
+            prefix: prefix.into(),
+        }])?;
+        Ok(())
+    }
+
+    async fn txn(&self, txn: KVTxn) -> Result<KVTxnResponse> {
+        if txn.success.len().max(txn.failure.len()) > MAX_TXN_OPS {
+            return Err(anyhow!("too many operations in txn request"));
+        }
+        let mut state = self.connected()?;
+        let succeeded = txn
+            .compares
+            .iter()
+            .all(|c| c.matches(state.kvs.get(&c.key)));
+        let (gets, ranges) = if succeeded {
+            state.apply(&txn.success)?
+        } else {
+            state.apply(&txn.failure)?
+        };
+        Ok(KVTxnResponse {
+            succeeded,
+            revision: state.revision,
+            gets,
+            ranges,
+        })
+    }
+
+    async fn watch_prefix(
+        &self,
+        prefix: &[u8],
+        options: KVWatchOptions,
+    ) -> Result<Box<dyn KVWatchStream>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        sender.send(KVWatchResponse {
+            created: true,
+            ..Default::default()
+        })?;
+
+        let mut state = self.connected()?;
+        if options.start_revision > 0 {
+            if options.start_revision <= state.compact_revision {
+                sender.send(KVWatchResponse {
+                    canceled: true,
+                    compact_revision: state.compact_revision,
+                    ..Default::default()
+                })?;
+                return Ok(Box::new(InMemoryWatchStream {
+                    id: 0,
+                    receiver,
+                    state: Weak::new(),
+                }));
+            }
+
+            let replay = watched(
+                state
+                    .history
+                    .iter()
+                    .filter(|e| e.kv.mod_revision >= options.start_revision),
+                prefix,
+                options.prev_kv,
+            );
+            for revision in replay.chunk_by(|a, b| a.kv.mod_revision == b.kv.mod_revision) {
+                sender.send(KVWatchResponse {
+                    events: revision.to_vec(),
+                    ..Default::default()
+                })?;
+            }
+        }
+
+        state.last_watch_id += 1;
+        let id = state.last_watch_id;
+        state.watchers.insert(
+            id,
+            WatchSlot {
+                prefix: prefix.into(),
+                prev_kv: options.prev_kv,
+                sender,
+            },
+        );
+
+        Ok(Box::new(InMemoryWatchStream {
+            id,
+            receiver,
+            state: Arc::downgrade(&self.state),
+        }))
+    }
+
+    async fn lease_grant(&self, ttl: i64) -> Result<i64> {
+        let (id, start_reaper) = {
+            let mut state = self.connected()?;
+            state.last_lease_id += 1;
+            let id = state.last_lease_id;
+            state.leases.insert(
+                id,
+                Lease {
+                    ttl,
+                    deadline: Instant::now() + Duration::from_secs(ttl as u64),
+                    keys: BTreeSet::default(),
+                },
+            );
+            (id, !std::mem::replace(&mut state.reaper_started, true))
+        };
+        if start_reaper {
+            self.spawn_lease_reaper();
+        }
+        Ok(id)
+    }
+
+    async fn lease_keep_alive(&self, lease_id: i64) -> Result<i64> {
+        // like etcd, keeping alive an unknown lease is not an error but a zero TTL
+        Ok(match self.connected()?.leases.get_mut(&lease_id) {
+            Some(lease) => {
+                lease.deadline = Instant::now() + Duration::from_secs(lease.ttl as u64);
+                lease.ttl
+            }
+            None => 0,
+        })
+    }
+
+    async fn lease_time_to_live(&self, lease_id: i64) -> Result<i64> {
+        Ok(match self.connected()?.leases.get(&lease_id) {
+            Some(lease) => {
+                let remaining = lease.deadline.saturating_duration_since(Instant::now());
+                (remaining.as_millis() as i64 + 999) / 1000
+            }
+            None => -1,
+        })
+    }
+
+    async fn lease_revoke(&self, lease_id: i64) -> Result<()> {
+        if self.connected()?.revoke(lease_id) {
+            Ok(())
+        } else {
+            Err(ConfigError::LeaseNotFound(lease_id).into())
+        }
+    }
+
+    async fn reconnect(&self) -> Result<()> {
+        self.connected().map(|_| ())
+    }
+
+    async fn campaign(&self, name: &str, value: &str, lease_id: i64) -> Result<KVLeaderKey> {
+        let key = KVBytes::from(format!("{}/{:x}", name, lease_id));
+        let rev = {
+            let mut state = self.connected()?;
+            let rev = match state.kvs.get(&key) {
+                Some(kv) => kv.create_revision,
+                None => state.revision + 1,
+            };
+            state.apply(&[KVTxnOp::Put {
+                key: key.clone(),
+                value: value.into(),
+                lease_id: Some(lease_id),
+            }])?;
+            rev
+        };
+        let mut candidate = Candidate {
+            state: Arc::downgrade(&self.state),
+            key: Some((key.clone(), rev)),
+        };
+
+        let mut watch = self
+            .watch_prefix(format!("{}/", name).as_bytes(), KVWatchOptions::default())
+            .await?;
+        loop {
+            {
+                let state = self.connected()?;
+                match state.election_leader(name) {
+                    Some(kv) if kv.key == key => break,
+                    _ if !state.kvs.contains_key(&key) => {
+                        return Err(ConfigError::CampaignAborted(name.to_string()).into())
+                    }
+                    _ => (),
+                }
+            }
+            if watch.message().await?.is_none() {
+                return Err(ConfigError::BackendUnavailable.into());
+            }
+        }
+
+        candidate.key = None;
+        Ok(KVLeaderKey {
+            name: name.to_string(),
+            key: key.to_string(),
+            rev,
+            lease: lease_id,
+        })
+    }
+
+    async fn proclaim(&self, leader: &KVLeaderKey, value: &str) -> Result<()> {
+        let resp = self
+            .txn(KVTxn {
+                compares: vec![KVCompare {
+                    key: leader.key.as_str().into(),
+                    target: KVCompareTarget::CreateRevision(leader.rev),
+                }],
+                success: vec![KVTxnOp::Put {
+                    key: leader.key.as_str().into(),
+                    value: value.into(),
+                    lease_id: Some(leader.lease),
+                }],
+                failure: Vec::default(),
+            })
+            .await?;
+        if !resp.succeeded {
+            return Err(ConfigError::NotLeader(leader.name.clone()).into());
+        }
+        Ok(())
+    }
+
+    async fn leader(&self, name: &str) -> Result<Option<KVEntry>> {
+        Ok(self.connected()?.election_leader(name).cloned())
+    }
+
+    async fn observe(&self, name: &str) -> Result<Box<dyn KVLeaderStream>> {
+        let watch = self
+            .watch_prefix(format!("{}/", name).as_bytes(), KVWatchOptions::default())
+            .await?;
+        Ok(Box::new(InMemoryLeaderStream {
+            backend: self.clone(),
+            name: name.to_string(),
+            watch,
+            last: None,
+        }))
+    }
+
+    async fn resign(&self, leader: &KVLeaderKey) -> Result<()> {
+        // like etcd, resigning a lost election is not an error
+        self.txn(KVTxn {
+            compares: vec![KVCompare {
+                key: leader.key.as_str().into(),
+                target: KVCompareTarget::CreateRevision(leader.rev),
+            }],
+            success: vec![KVTxnOp::Delete {
+                key: leader.key.as_str().into(),
+            }],
+            failure: Vec::default(),
+        })
+        .await?;
+        Ok(())
+    }
+}
+
+/// Removes the candidate key of a campaign that was abandoned before winning.
+struct Candidate {
+    state: Weak<Mutex<State>>,
+    key: Option<(KVBytes, i64)>,
+}
+
+impl Drop for Candidate {
+    fn drop(&mut self) {
+        if let (Some((key, rev)), Some(state)) = (self.key.take(), self.state.upgrade()) {
+            let mut state = state.lock().unwrap();
+            if state.kvs.get(&key).map(|kv| kv.create_revision) == Some(rev) {
+                let _ = state.apply(&[KVTxnOp::Delete { key }]);
+            }
+        }
+    }
+}
+
+struct InMemoryLeaderStream {
+    backend: InMemoryBackend,
+    name: String,
+    watch: Box<dyn KVWatchStream>,
+    last: Option<KVEntry>,
+}
+
+#[async_trait]
+impl KVLeaderStream for InMemoryLeaderStream {
+    async fn message(&mut self) -> Result<Option<KVEntry>> {
+        loop {
+            let leader = self.backend.leader(&self.name).await?;
+            if leader.is_some() && leader != self.last {
+                self.last = leader.clone();
+                return Ok(leader);
+            }
+            if self.watch.message().await?.is_none() {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+struct InMemoryWatchStream {
+    id: u64,
+    receiver: mpsc::UnboundedReceiver<KVWatchResponse>,
+    state: Weak<Mutex<State>>,
+}
+
+#[async_trait]
+impl KVWatchStream for InMemoryWatchStream {
+    async fn message(&mut self) -> Result<Option<KVWatchResponse>> {
+        Ok(self.receiver.recv().await)
+    }
+
+    /// Fails like etcd once the stream has been closed by an outage.
+    async fn cancel(&mut self) -> Result<()> {
+        if let Some(state) = self.state.upgrade() {
+            match state.lock().unwrap().watchers.remove(&self.id) {
+                Some(slot) => {
+                    let _ = slot.sender.send(KVWatchResponse {
+                        canceled: true,
+                        ..Default::default()
+                    });
+                }
+                None => return Err(anyhow!("watch stream is closed")),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv_backend::{KVBackend, KVEventType, KVGetOptions, KVWatchOptions};
+    use crate::memory_backend::InMemoryBackend;
+    use anyhow::Result;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_lease_expiration() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut watch = backend
+            .watch_prefix(b"node", KVWatchOptions::default())
+            .await?;
+        assert!(watch.message().await?.unwrap().created);
+
+        let lease_id = backend.lease_grant(1).await?;
+        backend
+            .put(b"node/leased", b"value", Some(lease_id))
+            .await?;
+        backend.put(b"node/static", b"value", None).await?;
+        assert_eq!(backend.get_prefix(b"node").await?.kvs.len(), 2);
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        assert_eq!(backend.get(b"node/leased").await?, None);
+        assert!(backend.get(b"node/static").await?.is_some());
+        assert!(backend
+            .put(b"node/other", b"value", Some(lease_id))
+            .await
+            .is_err());
+
+        let mut events = Vec::default();
+        while events.len() < 3 {
+            events.append(&mut watch.message().await?.unwrap().events);
+        }
+        assert_eq!(events[2].event_type, KVEventType::Delete);
+        assert_eq!(events[2].kv.key, "node/leased");
+        assert_eq!(events[2].kv.mod_revision, backend.revision());
+
+        watch.cancel().await?;
+        assert!(watch.message().await?.unwrap().canceled);
+        assert!(watch.message().await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_binary_keys_and_values() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        backend.put(b"bin/\xff\x00", b"\x00\x01\xfe", None).await?;
+        backend.put(b"bin/\xff\x01", b"\xc3\x28", None).await?;
+        backend.put(b"bin0", b"outside", None).await?;
+
+        let kv = backend.get(b"bin/\xff\x00").await?.unwrap();
+        assert_eq!(kv.value.as_bytes(), b"\x00\x01\xfe");
+        assert!(kv.value.as_str().is_err());
+
+        let kvs = backend.get_prefix(b"bin/\xff").await?.kvs;
+        assert_eq!(kvs.len(), 2);
+        assert_eq!(kvs[1].value, b"\xc3\x28"[..]);
+
+        backend.delete_prefix(b"bin/").await?;
+        assert_eq!(backend.get_prefix(b"bin").await?.kvs.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_range() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        for key in ["a", "b", "c", "d"] {
+            backend.put(key.as_bytes(), b"v1", None).await?;
+        }
+        let revision = backend.revision();
+        backend.put(b"b", b"v2", None).await?;
+        backend.delete(b"c").await?;
+
+        let page = |limit, revision| KVGetOptions {
+            limit,
+            revision,
+            ..Default::default()
+        };
+        let resp = backend.get_range(b"b", b"", page(2, 0)).await?;
+        assert_eq!((resp.count, resp.more), (2, false));
+        assert_eq!(resp.kvs[0].value, "v2");
+
+        let resp = backend.get_range(b"a", b"d", page(2, revision)).await?;
+        assert_eq!((resp.count, resp.more), (3, true));
+        assert_eq!(
+            resp.kvs
+                .iter()
+                .map(|kv| (&kv.key, &kv.value))
+                .collect::<Vec<_>>(),
+            vec![(&"a".into(), &"v1".into()), (&"b".into(), &"v1".into())]
+        );
+        assert_eq!(resp.revision, backend.revision());
+
+        backend.compact(revision);
+        let resp = backend
+            .get_range(
+                b"",
+                b"",
+                KVGetOptions {
+                    revision,
+                    keys_only: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        assert_eq!(resp.kvs.len(), 4);
+        assert!(resp.kvs.iter().all(|kv| kv.value.is_empty()));
+
+        let resp = backend
+            .get_range(
+                b"a",
+                b"",
+                KVGetOptions {
+                    count_only: true,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        assert_eq!((resp.count, resp.kvs.len()), (3, 0));
+
+        backend.compact(backend.revision());
+        assert!(backend
+            .get_range(b"", b"", page(0, revision))
+            .await
+            .is_err());
+        Ok(())
+    }
+}
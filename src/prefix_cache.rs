@@ -0,0 +1,149 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::kv_backend::{KVBytes, KVEntry, KVEvent, KVEventType};
+
+/// Contents of the cached prefix as of `revision`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheSnapshot {
+    pub kvs: BTreeMap<KVBytes, KVEntry>,
+    pub revision: i64,
+}
+
+/// Mirror of a watched prefix. Every update publishes a new snapshot, so readers
+/// never wait for the writer and always see a single revision.
+#[derive(Clone, Default)]
+pub struct PrefixCache {
+    snapshot: Arc<ArcSwap<CacheSnapshot>>,
+}
+
+impl PrefixCache {
+    pub fn new(kvs: Vec<KVEntry>, revision: i64) -> PrefixCache {
+        PrefixCache::from_snapshot(CacheSnapshot {
+            kvs: kvs.into_iter().map(|kv| (kv.key.clone(), kv)).collect(),
+            revision,
+        })
+    }
+
+    pub fn from_snapshot(snapshot: CacheSnapshot) -> PrefixCache {
+        PrefixCache {
+            snapshot: Arc::new(ArcSwap::from_pointee(snapshot)),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<KVBytes> {
+        self.snapshot.load().kvs.get(key).map(|kv| kv.value.clone())
+    }
+
+    pub fn entry(&self, key: &[u8]) -> Option<KVEntry> {
+        self.snapshot.load().kvs.get(key).cloned()
+    }
+
+    /// Pairs of the keys starting with `prefix`, in key order.
+    pub fn list(&self, prefix: &[u8]) -> Vec<(KVBytes, KVBytes)> {
+        self.snapshot
+            .load()
+            .kvs
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, kv)| (k.clone(), kv.value.clone()))
+            .collect()
+    }
+
+    pub fn revision(&self) -> i64 {
+        self.snapshot.load().revision
+    }
+
+    /// Current snapshot, for several reads at the same revision.
+    pub fn snapshot(&self) -> Arc<CacheSnapshot> {
+        self.snapshot.load_full()
+    }
+
+    /// Publishes the snapshot with the events applied, as of `revision`.
+    pub fn apply(&self, events: &[KVEvent], revision: i64) {
+        let mut snapshot = CacheSnapshot::clone(&self.snapshot.load());
+        for event in events {
+            match event.event_type {
+                KVEventType::Put => {
+                    snapshot.kvs.insert(event.kv.key.clone(), event.kv.clone());
+                }
+                KVEventType::Delete => {
+                    snapshot.kvs.remove(&event.kv.key);
+                }
+            }
+        }
+        snapshot.revision = revision;
+        self.snapshot.store(Arc::new(snapshot));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv_backend::{KVEntry, KVEvent, KVEventType};
+    use crate::prefix_cache::PrefixCache;
+
+    fn entry(key: &str, value: &str, revision: i64) -> KVEntry {
+        KVEntry {
+            key: key.into(),
+            value: value.into(),
+            mod_revision: revision,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let cache = PrefixCache::new(vec![entry("app/a", "a", 1), entry("app/b/c", "c", 2)], 2);
+        let reader = cache.clone();
+        let before = reader.snapshot();
+
+        cache.apply(
+            &[
+                KVEvent {
+                    event_type: KVEventType::Put,
+                    kv: entry("app/b/d", "d", 3),
+                    prev_kv: None,
+                },
+                KVEvent {
+                    event_type: KVEventType::Delete,
+                    kv: entry("app/a", "", 3),
+                    prev_kv: None,
+                },
+            ],
+            3,
+        );
+
+        assert_eq!(reader.revision(), 3);
+        assert_eq!(reader.get(b"app/a"), None);
+        assert_eq!(reader.get(b"app/b/d"), Some("d".into()));
+        assert_eq!(
+            reader.list(b"app/b/"),
+            vec![
+                ("app/b/c".into(), "c".into()),
+                ("app/b/d".into(), "d".into())
+            ]
+        );
+        assert_eq!(reader.entry(b"app/b/c").unwrap().mod_revision, 2);
+
+        assert_eq!(before.revision, 2);
+        assert!(before.kvs.contains_key(b"app/a".as_slice()));
+    }
+}
@@ -0,0 +1,402 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::kv_backend::{
+    prefix_end, KVBackend, KVBytes, KVEntry, KVEvent, KVEventType, KVGetOptions, KVWatchOptions,
+    KVWatchResponse, KVWatchStream,
+};
+
+const PAGE_SIZE: i64 = 1000;
+
+/// Watch over a prefix that survives the end of the underlying stream: it is
+/// re-created from the last seen revision, and when that revision has been
+/// compacted the prefix is re-read and the difference is reported as events.
+/// Events carry the previous state of the key, except for those of such a
+/// difference.
+pub struct PrefixWatch {
+    backend: Arc<dyn KVBackend>,
+    prefix: String,
+    stream: Box<dyn KVWatchStream>,
+    revision: i64,
+    known: BTreeMap<KVBytes, i64>,
+    canceled: bool,
+}
+
+impl PrefixWatch {
+    pub async fn new(backend: Arc<dyn KVBackend>, prefix: &str) -> Result<PrefixWatch> {
+        let (kvs, revision) = read_prefix(&*backend, prefix, 0, true).await?;
+        PrefixWatch::start(backend, prefix, &kvs, revision).await
+    }
+
+    /// Also returns the entries of the prefix the watch starts from.
+    pub async fn with_snapshot(
+        backend: Arc<dyn KVBackend>,
+        prefix: &str,
+    ) -> Result<(PrefixWatch, Vec<KVEntry>)> {
+        let (kvs, revision) = read_prefix(&*backend, prefix, 0, false).await?;
+        let watch = PrefixWatch::start(backend, prefix, &kvs, revision).await?;
+        Ok((watch, kvs))
+    }
+
+    /// Watches the changes made after `revision`, which fails with
+    /// `ConfigError::RevisionUnavailable` once it is compacted.
+    pub async fn from_revision(
+        backend: Arc<dyn KVBackend>,
+        prefix: &str,
+        revision: i64,
+    ) -> Result<PrefixWatch> {
+        let (kvs, revision) = read_prefix(&*backend, prefix, revision, true).await?;
+        PrefixWatch::start(backend, prefix, &kvs, revision).await
+    }
+
+    /// Starts watching from the current contents and returns the changes relative
+    /// to `known`, the mod revisions of a previously seen state of the prefix.
+    pub async fn reconcile(
+        backend: Arc<dyn KVBackend>,
+        prefix: &str,
+        known: BTreeMap<KVBytes, i64>,
+    ) -> Result<(PrefixWatch, Vec<KVEvent>)> {
+        let (kvs, revision) = read_prefix(&*backend, prefix, 0, true).await?;
+        let events = changes(&*backend, &known, &kvs, revision).await?;
+        let watch = PrefixWatch::start(backend, prefix, &kvs, revision).await?;
+        Ok((watch, events))
+    }
+
+    async fn start(
+        backend: Arc<dyn KVBackend>,
+        prefix: &str,
+        kvs: &[KVEntry],
+        revision: i64,
+    ) -> Result<PrefixWatch> {
+        let stream = backend
+            .watch_prefix(
+                prefix.as_bytes(),
+                KVWatchOptions {
+                    start_revision: revision + 1,
+                    prev_kv: true,
+                },
+            )
+            .await?;
+        Ok(PrefixWatch {
+            backend,
+            prefix: prefix.to_string(),
+            stream,
+            revision,
+            known: kvs
+                .iter()
+                .map(|kv| (kv.key.clone(), kv.mod_revision))
+                .collect(),
+            canceled: false,
+        })
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Tells whether the key exists as of the last delivered revision.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.known.contains_key(key)
+    }
+
+    /// Last revision delivered to the caller.
+    pub fn revision(&self) -> i64 {
+        self.revision
+    }
+
+    /// Waits for the next non-empty batch of events, `None` once the watch has been
+    /// canceled with `cancel`. Not cancel safe while the prefix is re-read, use
+    /// `receive` and `process` to wait for other things too.
+    pub async fn next(&mut self) -> Result<Option<Vec<KVEvent>>> {
+        loop {
+            let received = self.receive().await;
+            match self.process(received).await? {
+                Some(events) if events.is_empty() => continue,
+                res => return Ok(res),
+            }
+        }
+    }
+
+    /// Waits for the next response of the underlying stream. Cancel safe.
+    pub async fn receive(&mut self) -> Result<Option<KVWatchResponse>> {
+        self.stream.message().await
+    }
+
+    /// Turns what `receive` returned into events, empty when there is nothing to
+    /// deliver, resuming the stream or re-reading the prefix as needed. `None`
+    /// once the watch has been canceled with `cancel`.
+    pub async fn process(
+        &mut self,
+        received: Result<Option<KVWatchResponse>>,
+    ) -> Result<Option<Vec<KVEvent>>> {
+        let resp = match received {
+            Ok(Some(resp)) => resp,
+            _ if self.canceled => return Ok(None),
+            Ok(None) => {
+                warn!("Watch on {} was closed, resuming", &self.prefix);
+                self.resume().await?;
+                return Ok(Some(Vec::default()));
+            }
+            Err(e) => {
+                warn!("Watch on {} failed: {}, resuming", &self.prefix, e);
+                self.resume().await?;
+                return Ok(Some(Vec::default()));
+            }
+        };
+
+        if resp.compact_revision > 0 {
+            warn!(
+                "Revision {} of {} is compacted, re-reading the prefix",
+                self.revision + 1,
+                &self.prefix
+            );
+            return Ok(Some(self.resync().await?));
+        } else if resp.canceled && self.canceled {
+            return Ok(None);
+        } else if resp.canceled {
+            warn!("Watch on {} was canceled, resuming", &self.prefix);
+            self.resume().await?;
+            return Ok(Some(Vec::default()));
+        } else if resp.created {
+            info!("Etcd datcher was successfully deployed.");
+        }
+
+        self.apply(&resp.events);
+        Ok(Some(resp.events))
+    }
+
+    pub async fn cancel(&mut self) -> Result<()> {
+        if self.canceled {
+            return Ok(());
+        }
+        self.canceled = true;
+        self.stream.cancel().await
+    }
+
+    /// Re-creates the underlying stream from the last delivered revision.
+    pub async fn resume(&mut self) -> Result<()> {
+        self.stream = self
+            .backend
+            .watch_prefix(
+                self.prefix.as_bytes(),
+                KVWatchOptions {
+                    start_revision: self.revision + 1,
+                    prev_kv: true,
+                },
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn resync(&mut self) -> Result<Vec<KVEvent>> {
+        let (kvs, revision) = read_prefix(&*self.backend, &self.prefix, 0, true).await?;
+        let events = changes(&*self.backend, &self.known, &kvs, revision).await?;
+        let stream = self
+            .backend
+            .watch_prefix(
+                self.prefix.as_bytes(),
+                KVWatchOptions {
+                    start_revision: revision + 1,
+                    prev_kv: true,
+                },
+            )
+            .await?;
+
+        self.stream = stream;
+        self.known = kvs
+            .iter()
+            .map(|kv| (kv.key.clone(), kv.mod_revision))
+            .collect();
+        self.revision = revision;
+        Ok(events)
+    }
+
+    fn apply(&mut self, events: &[KVEvent]) {
+        for event in events {
+            match event.event_type {
+                KVEventType::Put => {
+                    self.known
+                        .insert(event.kv.key.clone(), event.kv.mod_revision);
+                }
+                KVEventType::Delete => {
+                    self.known.remove(&event.kv.key);
+                }
+            }
+            self.revision = self.revision.max(event.kv.mod_revision);
+        }
+    }
+}
+
+/// Reads the prefix in pages at a single revision, the current one when zero,
+/// and returns the entries with that revision.
+async fn read_prefix(
+    backend: &dyn KVBackend,
+    prefix: &str,
+    revision: i64,
+    keys_only: bool,
+) -> Result<(Vec<KVEntry>, i64)> {
+    let to = prefix_end(prefix.as_bytes());
+    let mut from = prefix.as_bytes().to_vec();
+    let mut options = KVGetOptions {
+        limit: PAGE_SIZE,
+        revision,
+        keys_only,
+        ..Default::default()
+    };
+    let mut kvs = Vec::default();
+    loop {
+        let resp = backend.get_range(&from, &to, options.clone()).await?;
+        if options.revision == 0 {
+            options.revision = resp.revision;
+        }
+        kvs.extend(resp.kvs);
+        match kvs.last() {
+            Some(last) if resp.more => {
+                from = last.key.to_vec();
+                from.push(0);
+            }
+            _ => return Ok((kvs, options.revision)),
+        }
+    }
+}
+
+/// Events turning `known` into the keys-only `kvs` read at `revision`, the changed
+/// keys are read again for their values.
+async fn changes(
+    backend: &dyn KVBackend,
+    known: &BTreeMap<KVBytes, i64>,
+    kvs: &[KVEntry],
+    revision: i64,
+) -> Result<Vec<KVEvent>> {
+    let mut events = Vec::default();
+    for kv in kvs {
+        if known.get(&kv.key) == Some(&kv.mod_revision) {
+            continue;
+        }
+        let mut to = kv.key.to_vec();
+        to.push(0);
+        let options = KVGetOptions {
+            revision,
+            ..Default::default()
+        };
+        let resp = backend.get_range(kv.key.as_bytes(), &to, options).await?;
+        events.push(KVEvent {
+            event_type: KVEventType::Put,
+            kv: resp.kvs.into_iter().next().unwrap_or_else(|| kv.clone()),
+            prev_kv: None,
+        });
+    }
+    let current: BTreeSet<_> = kvs.iter().map(|kv| &kv.key).collect();
+    for key in known.keys().filter(|k| !current.contains(k)) {
+        events.push(KVEvent {
+            event_type: KVEventType::Delete,
+            kv: KVEntry {
+                key: key.clone(),
+                mod_revision: revision,
+                ..Default::default()
+            },
+            prev_kv: None,
+        });
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv_backend::{KVBackend, KVEventType};
+    use crate::memory_backend::InMemoryBackend;
+    use crate::prefix_watch::PrefixWatch;
+    use anyhow::Result;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_resume() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        backend.put(b"node/a", b"a", None).await?;
+        backend.put(b"node/b", b"b", None).await?;
+
+        let mut watch = PrefixWatch::new(Arc::new(backend.clone()), "node").await?;
+        backend.put(b"node/a", b"a1", None).await?;
+        let events = watch.next().await?.unwrap();
+        assert_eq!(events[0].kv.value, "a1");
+
+        backend.disconnect_watchers();
+        backend.put(b"node/c", b"c", None).await?;
+        let events = watch.next().await?.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kv.key, "node/c");
+        assert_eq!(watch.revision(), backend.revision());
+
+        backend.disconnect_watchers();
+        backend.put(b"node/c", b"c1", None).await?;
+        backend.delete(b"node/a").await?;
+        backend.put(b"node/d", b"d", None).await?;
+        backend.delete(b"node/d").await?;
+        backend.compact(backend.revision());
+
+        let events: Vec<_> = watch
+            .next()
+            .await?
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.event_type, e.kv.key, e.kv.value))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (KVEventType::Put, "node/c".into(), "c1".into()),
+                (KVEventType::Delete, "node/a".into(), "".into()),
+            ]
+        );
+        assert_eq!(watch.revision(), backend.revision());
+
+        backend.put(b"node/b", b"b1", None).await?;
+        assert_eq!(watch.next().await?.unwrap()[0].kv.value, "b1");
+
+        watch.cancel().await?;
+        assert_eq!(watch.next().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_paged_snapshot() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        for i in 0..2500 {
+            backend
+                .put(format!("node/{:04}", i).as_bytes(), b"v", None)
+                .await?;
+        }
+
+        let (mut watch, kvs) =
+            PrefixWatch::with_snapshot(Arc::new(backend.clone()), "node").await?;
+        assert_eq!(kvs.len(), 2500);
+        assert_eq!(kvs[2499].value, "v");
+        assert!(watch.contains(b"node/2499"));
+
+        backend.delete(b"node/0000").await?;
+        backend.compact(backend.revision());
+        let events = watch.next().await?.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, KVEventType::Delete);
+        assert!(!watch.contains(b"node/0000"));
+        Ok(())
+    }
+}
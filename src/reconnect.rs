@@ -0,0 +1,93 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::time::Duration;
+
+use rand::Rng;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// Fraction of the delay randomly added or subtracted, `0.0` disables jitter.
+    pub jitter: f64,
+    /// Attempts before giving up, `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Fails on the first connectivity error, as `ConfClient` did originally.
+    pub fn disabled() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(0),
+            ..Default::default()
+        }
+    }
+
+    pub fn exhausted(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt > max)
+    }
+
+    /// Delay before the given attempt, starting from 1.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let delay = (self.initial_backoff.as_secs_f64() * exp).min(self.max_backoff.as_secs_f64());
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+        Duration::from_secs_f64((delay * (1.0 + jitter)).max(0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reconnect::ReconnectPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let policy = ReconnectPolicy {
+            jitter: 0.0,
+            max_attempts: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(800));
+        assert_eq!(policy.backoff(20), Duration::from_secs(10));
+        assert!(!policy.exhausted(5));
+        assert!(policy.exhausted(6));
+
+        let policy = ReconnectPolicy::default();
+        for _ in 0..100 {
+            let delay = policy.backoff(2);
+            assert!(delay >= Duration::from_millis(320) && delay <= Duration::from_millis(480));
+        }
+    }
+}
@@ -0,0 +1,189 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::kv_backend::{KVBackend, KVBytes, KVEventType};
+use crate::prefix_watch::PrefixWatch;
+
+/// Service instance, stored as JSON under `<service prefix>/<id>`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServiceInstance {
+    pub id: String,
+    pub address: String,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiscoveryEvent {
+    Joined(ServiceInstance),
+    Updated(ServiceInstance),
+    Left(ServiceInstance),
+}
+
+pub fn instance_key(service: &str, id: &str) -> String {
+    format!("{}/{}", service, id)
+}
+
+/// Live instances of a service, kept up to date from a watch on its prefix.
+pub struct ServiceDiscovery {
+    watcher: PrefixWatch,
+    instances: BTreeMap<KVBytes, ServiceInstance>,
+}
+
+impl ServiceDiscovery {
+    pub async fn new(backend: Arc<dyn KVBackend>, service: &str) -> Result<ServiceDiscovery> {
+        let prefix = instance_key(service, "");
+        let (watcher, kvs) = PrefixWatch::with_snapshot(backend, &prefix).await?;
+        let mut instances = BTreeMap::default();
+        for kv in kvs {
+            if let Some(instance) = parse(&kv.key, &kv.value) {
+                instances.insert(kv.key, instance);
+            }
+        }
+        Ok(ServiceDiscovery { watcher, instances })
+    }
+
+    pub fn instances(&self) -> Vec<ServiceInstance> {
+        self.instances.values().cloned().collect()
+    }
+
+    /// Waits for the next membership changes. Cancel safe.
+    pub async fn next(&mut self) -> Result<Option<Vec<DiscoveryEvent>>> {
+        loop {
+            let events = match self.watcher.next().await? {
+                Some(events) => events,
+                None => return Ok(None),
+            };
+            let mut changes = Vec::default();
+            for event in events {
+                match event.event_type {
+                    KVEventType::Put => {
+                        let instance = match parse(&event.kv.key, &event.kv.value) {
+                            Some(instance) => instance,
+                            None => continue,
+                        };
+                        match self.instances.insert(event.kv.key, instance.clone()) {
+                            None => changes.push(DiscoveryEvent::Joined(instance)),
+                            Some(prev) if prev != instance => {
+                                changes.push(DiscoveryEvent::Updated(instance))
+                            }
+                            Some(_) => (),
+                        }
+                    }
+                    KVEventType::Delete => {
+                        if let Some(instance) = self.instances.remove(&event.kv.key) {
+                            changes.push(DiscoveryEvent::Left(instance));
+                        }
+                    }
+                }
+            }
+            if !changes.is_empty() {
+                return Ok(Some(changes));
+            }
+        }
+    }
+
+    pub async fn cancel(&mut self) -> Result<()> {
+        self.watcher.cancel().await
+    }
+}
+
+fn parse(key: &KVBytes, value: &KVBytes) -> Option<ServiceInstance> {
+    match serde_json::from_slice(value) {
+        Ok(instance) => Some(instance),
+        Err(e) => {
+            warn!("Ignoring malformed service instance {}: {}", key, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::etcd_conf::ConfClient;
+    use crate::kv_backend::KVBackend;
+    use crate::memory_backend::InMemoryBackend;
+    use crate::registry::{DiscoveryEvent, ServiceInstance};
+    use anyhow::Result;
+    use std::sync::Arc;
+
+    fn instance(id: &str, address: &str) -> ServiceInstance {
+        ServiceInstance {
+            id: id.into(),
+            address: address.into(),
+            metadata: [("zone".to_string(), "a".to_string())].into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_discovery() -> Result<()> {
+        let backend = InMemoryBackend::new();
+        let mut first =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+        let mut second =
+            ConfClient::with_backend(Arc::new(backend.clone()), "local/node".into(), 5).await?;
+
+        first
+            .register("services/api", &instance("one", "10.0.0.1:80"))
+            .await?;
+        backend.put(b"services/api/broken", b"{", None).await?;
+        let mut discovery = first.discovery("services/api").await?;
+        assert_eq!(discovery.instances(), vec![instance("one", "10.0.0.1:80")]);
+
+        second
+            .register("services/api", &instance("two", "10.0.0.2:80"))
+            .await?;
+        assert_eq!(
+            discovery.next().await?,
+            Some(vec![DiscoveryEvent::Joined(instance("two", "10.0.0.2:80"))])
+        );
+
+        first
+            .register("services/api", &instance("one", "10.0.0.1:81"))
+            .await?;
+        assert_eq!(
+            discovery.next().await?,
+            Some(vec![DiscoveryEvent::Updated(instance(
+                "one",
+                "10.0.0.1:81"
+            ))])
+        );
+
+        backend.lease_revoke(second.get_lease_id().unwrap()).await?;
+        assert_eq!(
+            discovery.next().await?,
+            Some(vec![DiscoveryEvent::Left(instance("two", "10.0.0.2:80"))])
+        );
+
+        first.deregister("services/api", "one").await?;
+        assert_eq!(
+            discovery.next().await?,
+            Some(vec![DiscoveryEvent::Left(instance("one", "10.0.0.1:81"))])
+        );
+        assert!(discovery.instances().is_empty());
+        assert!(first.leased_keys().is_empty());
+
+        discovery.cancel().await?;
+        assert_eq!(discovery.next().await?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::errors::ConfigError;
+use crate::kv_backend::KVEntry;
+use crate::prefix_cache::CacheSnapshot;
+
+const SAVE_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    prefix: String,
+    revision: i64,
+    kvs: Vec<KVEntry>,
+}
+
+/// Local copy of the last known contents of a watched prefix.
+#[derive(Clone, Debug)]
+pub struct SnapshotFile {
+    path: PathBuf,
+}
+
+impl SnapshotFile {
+    pub fn new(path: impl Into<PathBuf>) -> SnapshotFile {
+        SnapshotFile { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the file atomically: the content goes to a synced temporary file
+    /// of its own which is then renamed over it, and the rename is synced too, so
+    /// a crash leaves the old or the new one.
+    pub async fn save(&self, prefix: &str, snapshot: &CacheSnapshot) -> Result<()> {
+        let data = serde_json::to_vec(&Stored {
+            prefix: prefix.to_string(),
+            revision: snapshot.revision,
+            kvs: snapshot.kvs.values().cloned().collect(),
+        })?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(".{}.tmp", Uuid::new_v4()));
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, &self.path).await?;
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+        Ok(())
+    }
+
+    /// `None` when nothing has been saved yet.
+    pub async fn load(&self, prefix: &str) -> Result<Option<CacheSnapshot>> {
+        let data = match tokio::fs::read(&self.path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let stored: Stored = serde_json::from_slice(&data)?;
+        if stored.prefix != prefix {
+            return Err(ConfigError::SnapshotPrefixMismatch(stored.prefix).into());
+        }
+        Ok(Some(CacheSnapshot {
+            kvs: stored
+                .kvs
+                .into_iter()
+                .map(|kv| (kv.key.clone(), kv))
+                .collect(),
+            revision: stored.revision,
+        }))
+    }
+}
+
+/// Saves the snapshots handed to `save` from a background task, the latest one
+/// at most every `SAVE_INTERVAL`, so a burst of changes costs a single write.
+pub struct SnapshotWriter {
+    file: SnapshotFile,
+    snapshots: watch::Sender<Arc<CacheSnapshot>>,
+    // never sent, dropping it cuts the pause between writes short
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl SnapshotWriter {
+    /// Starts from `snapshot`, which is expected to be saved already.
+    pub fn spawn(file: SnapshotFile, prefix: &str, snapshot: Arc<CacheSnapshot>) -> SnapshotWriter {
+        let (snapshots, receiver) = watch::channel(snapshot);
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(write_snapshots(
+            file.clone(),
+            prefix.to_string(),
+            receiver,
+            stopped,
+        ));
+        SnapshotWriter {
+            file,
+            snapshots,
+            stop,
+            task,
+        }
+    }
+
+    pub fn file(&self) -> &SnapshotFile {
+        &self.file
+    }
+
+    pub fn save(&self, snapshot: Arc<CacheSnapshot>) {
+        self.snapshots.send_replace(snapshot);
+    }
+
+    /// Stops the task once the latest snapshot is saved.
+    pub async fn close(self) -> Result<()> {
+        let SnapshotWriter {
+            snapshots,
+            stop,
+            task,
+            ..
+        } = self;
+        drop(snapshots);
+        drop(stop);
+        task.await?
+    }
+}
+
+async fn write_snapshots(
+    file: SnapshotFile,
+    prefix: String,
+    mut snapshots: watch::Receiver<Arc<CacheSnapshot>>,
+    mut stopped: oneshot::Receiver<()>,
+) -> Result<()> {
+    let mut saved = snapshots.borrow().revision;
+    while snapshots.changed().await.is_ok() {
+        let snapshot = snapshots.borrow_and_update().clone();
+        match file.save(&prefix, &snapshot).await {
+            Ok(()) => saved = snapshot.revision,
+            Err(e) => warn!("Unable to save the snapshot to {:?}: {}", file.path(), e),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(SAVE_INTERVAL) => {}
+            _ = &mut stopped => break,
+        }
+    }
+    let snapshot = snapshots.borrow().clone();
+    if snapshot.revision != saved {
+        file.save(&prefix, &snapshot).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::ConfigError;
+    use crate::kv_backend::KVEntry;
+    use crate::prefix_cache::{CacheSnapshot, PrefixCache};
+    use crate::snapshot_file::{SnapshotFile, SnapshotWriter};
+    use anyhow::Result;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_save_load() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", uuid::Uuid::new_v4()));
+        let file = SnapshotFile::new(&path);
+        assert_eq!(file.load("app").await?, None);
+
+        let cache = PrefixCache::new(
+            vec![KVEntry {
+                key: "app/bin".into(),
+                value: b"\xff\x00".as_slice().into(),
+                mod_revision: 7,
+                ..Default::default()
+            }],
+            9,
+        );
+        file.save("app", &cache.snapshot()).await?;
+        file.save("app", &cache.snapshot()).await?;
+        assert_eq!(file.load("app").await?.as_ref(), Some(&*cache.snapshot()));
+
+        let res = file.load("other").await;
+        assert!(matches!(
+            res.unwrap_err().downcast_ref::<ConfigError>(),
+            Some(ConfigError::SnapshotPrefixMismatch(prefix)) if prefix == "app"
+        ));
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_writer() -> Result<()> {
+        tokio::time::pause();
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", uuid::Uuid::new_v4()));
+        let file = SnapshotFile::new(&path);
+        let at = |revision| {
+            Arc::new(CacheSnapshot {
+                revision,
+                ..Default::default()
+            })
+        };
+        let saved = || async { file.load("app").await.map(|s| s.map(|s| s.revision)) };
+
+        let writer = SnapshotWriter::spawn(file.clone(), "app", at(1));
+        writer.save(at(2));
+        while saved().await? != Some(2) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // later changes wait out the pause after a write, closing cuts it short
+        writer.save(at(3));
+        writer.save(at(4));
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(saved().await?, Some(2));
+        let closing = tokio::time::Instant::now();
+        writer.close().await?;
+        assert!(closing.elapsed() < Duration::from_millis(100));
+        assert_eq!(saved().await?, Some(4));
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Result;
+use etcd_client::{Certificate, Identity, TlsOptions};
+
+use crate::errors::ConfigError;
+
+/// TLS settings of the etcd connection, endpoints without a scheme are then
+/// connected over `https`.
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    /// PEM encoded CA bundle the server certificate is verified against.
+    pub ca_cert: Option<Vec<u8>>,
+    /// PEM encoded client certificate and private key for mutual TLS.
+    pub identity: Option<(Vec<u8>, Vec<u8>)>,
+    /// Name the server certificate is verified against instead of the endpoint host.
+    pub domain_name: Option<String>,
+}
+
+/// Leaves the private key out of logs.
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field(
+                "ca_cert",
+                &self
+                    .ca_cert
+                    .as_ref()
+                    .map(|pem| String::from_utf8_lossy(pem)),
+            )
+            .field("identity", &self.identity.as_ref().map(|_| Redacted))
+            .field("domain_name", &self.domain_name)
+            .finish()
+    }
+}
+
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl TlsConfig {
+    pub fn from_files<P: AsRef<Path>>(
+        ca_cert: Option<P>,
+        identity: Option<(P, P)>,
+        domain_name: Option<String>,
+    ) -> Result<TlsConfig> {
+        let ca_cert = ca_cert.map(read).transpose()?;
+        let identity = match identity {
+            Some((cert, key)) => Some((read(cert)?, read(key)?)),
+            None => None,
+        };
+        Ok(TlsConfig {
+            ca_cert,
+            identity,
+            domain_name,
+        })
+    }
+
+    pub fn options(&self) -> TlsOptions {
+        let mut options = TlsOptions::new();
+        if let Some(ca_cert) = &self.ca_cert {
+            options = options.ca_certificate(Certificate::from_pem(ca_cert));
+        }
+        if let Some((cert, key)) = &self.identity {
+            options = options.identity(Identity::from_pem(cert, key));
+        }
+        if let Some(domain_name) = &self.domain_name {
+            options = options.domain_name(domain_name);
+        }
+        options
+    }
+}
+
+fn read<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let path = path.as_ref();
+    std::fs::read(path).map_err(|e| {
+        ConfigError::TlsFileUnreadable(path.display().to_string(), e.to_string()).into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv_backend::{EtcdBackend, KVBackend};
+    use crate::tls::TlsConfig;
+    use anyhow::Result;
+    use hyper::body::Bytes;
+    use hyper::header::HeaderValue;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::{Body, HeaderMap, Request, Response};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+    use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    fn certs(path: &str) -> Result<Vec<Certificate>> {
+        let pem = std::fs::read(path)?;
+        let certs = rustls_pemfile::certs(&mut pem.as_slice())?;
+        Ok(certs.into_iter().map(Certificate).collect())
+    }
+
+    /// Answers every unary call with an empty message.
+    async fn empty_reply(_: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from_static(&[0; 5])).await?;
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            sender.send_trailers(trailers).await
+        });
+        Ok(Response::builder()
+            .header("content-type", "application/grpc")
+            .body(body)
+            .unwrap())
+    }
+
+    /// Stand-in for etcd requiring a client certificate signed by the test CA,
+    /// its own certificate is issued for `etcd.test`.
+    async fn stand_in() -> Result<SocketAddr> {
+        let mut roots = RootCertStore::empty();
+        for cert in certs("assets/tls/ca.pem")? {
+            roots.add(&cert)?;
+        }
+        let key = std::fs::read("assets/tls/server.key")?;
+        let key = rustls_pemfile::pkcs8_private_keys(&mut key.as_slice())?.remove(0);
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+            .with_single_cert(certs("assets/tls/server.pem")?, PrivateKey(key))?;
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let _ = Http::new()
+                            .http2_only(true)
+                            .serve_connection(stream, service_fn(empty_reply))
+                            .await;
+                    }
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    async fn try_get(addr: SocketAddr, tls: TlsConfig) -> Result<()> {
+        let backend = EtcdBackend::connect(vec![addr.to_string()], None, Some(tls), 1).await?;
+        tokio::time::timeout(Duration::from_secs(2), backend.get(b"key")).await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() -> Result<()> {
+        let addr = stand_in().await?;
+        let tls = TlsConfig::from_files(
+            Some("assets/tls/ca.pem"),
+            Some(("assets/tls/client.pem", "assets/tls/client.key")),
+            Some("etcd.test".into()),
+        )?;
+        let debug = format!("{:?}", tls);
+        assert!(debug.contains("identity: Some(<redacted>)"));
+        assert!(!debug.contains("PRIVATE KEY"));
+        try_get(addr, tls.clone()).await?;
+
+        let anonymous = TlsConfig {
+            identity: None,
+            ..tls.clone()
+        };
+        assert!(try_get(addr, anonymous).await.is_err());
+
+        let wrong_name = TlsConfig {
+            domain_name: None,
+            ..tls
+        };
+        assert!(try_get(addr, wrong_name).await.is_err());
+
+        assert!(TlsConfig::from_files(Some("assets/tls/missing.pem"), None, None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+/**
+ * Copyright 2022 BWSoft Management, Ltd.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ * http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+use std::collections::{btree_map, BTreeMap};
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::de::value::StringDeserializer;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+use crate::errors::ConfigError;
+use crate::kv_backend::{KVBytes, KVEntry};
+
+/// Builds `T` from the keys under `prefix`: every path segment is a field of a
+/// nested struct (or a map key, or a sequence index), leaf values are parsed
+/// according to the field type.
+pub fn from_entries<T: DeserializeOwned>(
+    prefix: &str,
+    kvs: Vec<KVEntry>,
+) -> Result<T, ConfigError> {
+    let prefix = prefix.trim_end_matches('/');
+    let mut root = Node::new(prefix.to_string());
+    for kv in kvs {
+        let key = kv.key.as_str().map_err(|_| {
+            ConfigError::DeserializeError(kv.key.to_string(), "key is not valid UTF-8".into())
+        })?;
+        if key == prefix {
+            root.value = Some(kv.value);
+        } else if let Some(rel) = key.strip_prefix(prefix).and_then(|k| k.strip_prefix('/')) {
+            root.insert(rel.split('/').filter(|s| !s.is_empty()), kv.value);
+        }
+    }
+    let path = root.path.clone();
+    T::deserialize(root).map_err(|e| e.at(&path))
+}
+
+impl de::Error for ConfigError {
+    fn custom<M: Display>(msg: M) -> Self {
+        ConfigError::DeserializeError(String::new(), msg.to_string())
+    }
+}
+
+impl ConfigError {
+    /// Names the key a deserialization error happened at, unless already named.
+    fn at(self, key: &str) -> Self {
+        match self {
+            ConfigError::DeserializeError(k, msg) if k.is_empty() => {
+                ConfigError::DeserializeError(key.to_string(), msg)
+            }
+            e => e,
+        }
+    }
+}
+
+struct Node {
+    path: String,
+    value: Option<KVBytes>,
+    children: BTreeMap<String, Node>,
+}
+
+impl Node {
+    fn new(path: String) -> Node {
+        Node {
+            path,
+            value: None,
+            children: BTreeMap::default(),
+        }
+    }
+
+    fn insert<'a>(&mut self, mut segments: impl Iterator<Item = &'a str>, value: KVBytes) {
+        match segments.next() {
+            Some(segment) => {
+                let path = format!("{}/{}", &self.path, segment);
+                self.children
+                    .entry(segment.to_string())
+                    .or_insert_with(|| Node::new(path))
+                    .insert(segments, value)
+            }
+            None => self.value = Some(value),
+        }
+    }
+
+    fn value(&self) -> Result<&str, ConfigError> {
+        match &self.value {
+            Some(value) => value
+                .as_str()
+                .map_err(|_| self.error("value is not valid UTF-8")),
+            None => Err(self.error("expected a value, found a prefix")),
+        }
+    }
+
+    fn parse<T: FromStr>(&self, expected: &str) -> Result<T, ConfigError> {
+        let value = self.value()?;
+        value
+            .parse()
+            .map_err(|_| self.error(format!("expected {}, found `{}`", expected, value)))
+    }
+
+    fn error<M: Display>(&self, msg: M) -> ConfigError {
+        ConfigError::DeserializeError(self.path.clone(), msg.to_string())
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident: $ty:ty,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+                visitor.$visit(self.parse::<$ty>(stringify!($ty))?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Node {
+    type Error = ConfigError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        match self.value {
+            Some(value) if self.children.is_empty() => match value.as_str() {
+                Ok(_) => visitor.visit_string(value.into_string().unwrap()),
+                Err(_) => visitor.visit_byte_buf(value.into_vec()),
+            },
+            _ => self.deserialize_map(visitor),
+        }
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool: bool,
+        deserialize_i8 => visit_i8: i8,
+        deserialize_i16 => visit_i16: i16,
+        deserialize_i32 => visit_i32: i32,
+        deserialize_i64 => visit_i64: i64,
+        deserialize_u8 => visit_u8: u8,
+        deserialize_u16 => visit_u16: u16,
+        deserialize_u32 => visit_u32: u32,
+        deserialize_u64 => visit_u64: u64,
+        deserialize_f32 => visit_f32: f32,
+        deserialize_f64 => visit_f64: f64,
+        deserialize_char => visit_char: char,
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.value()?;
+        visitor.visit_string(self.value.unwrap().into_string().unwrap())
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        match self.value {
+            Some(value) => visitor.visit_byte_buf(value.into_vec()),
+            None => Err(self.error("expected a value, found a prefix")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        // an absent key never reaches the deserializer
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ConfigError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        // indexes are ordered numerically, so `10` follows `9`
+        let mut items: Vec<_> = self.children.into_iter().collect();
+        items.sort_by_key(|(k, _)| (k.parse::<u64>().unwrap_or(u64::MAX), k.clone()));
+        visitor.visit_seq(de::value::SeqDeserializer::new(
+            items.into_iter().map(|(_, node)| node),
+        ))
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        visitor.visit_map(NodeMap {
+            children: self.children.into_iter(),
+            value: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConfigError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConfigError> {
+        let value: StringDeserializer<ConfigError> = self.value()?.to_string().into_deserializer();
+        visitor.visit_enum(value)
+    }
+
+    forward_to_deserialize_any! {
+        unit unit_struct tuple tuple_struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, ConfigError> for Node {
+    type Deserializer = Node;
+
+    fn into_deserializer(self) -> Node {
+        self
+    }
+}
+
+struct NodeMap {
+    children: btree_map::IntoIter<String, Node>,
+    value: Option<Node>,
+}
+
+impl<'de> de::MapAccess<'de> for NodeMap {
+    type Error = ConfigError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, ConfigError> {
+        match self.children.next() {
+            Some((key, node)) => {
+                self.value = Some(node);
+                let key: StringDeserializer<ConfigError> = key.into_deserializer();
+                seed.deserialize(key).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, ConfigError> {
+        let node = self.value.take().expect("value is requested after its key");
+        let path = node.path.clone();
+        seed.deserialize(node).map_err(|e| e.at(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::ConfigError;
+    use crate::kv_backend::KVEntry;
+    use crate::typed::from_entries;
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Database {
+        host: String,
+        port: u16,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Mode {
+        Active,
+        Standby,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Config {
+        name: String,
+        enabled: bool,
+        ratio: f64,
+        mode: Mode,
+        db: Database,
+        tags: Vec<String>,
+        limits: BTreeMap<String, i64>,
+        comment: Option<String>,
+        #[serde(default)]
+        retries: u32,
+    }
+
+    fn entries(kvs: &[(&str, &str)]) -> Vec<KVEntry> {
+        kvs.iter()
+            .map(|(k, v)| KVEntry {
+                key: (*k).into(),
+                value: (*v).into(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_from_entries() {
+        let mut kvs = entries(&[
+            ("app/name", "svc"),
+            ("app/enabled", "true"),
+            ("app/ratio", "0.5"),
+            ("app/mode", "standby"),
+            ("app/db/host", "localhost"),
+            ("app/db/port", "5432"),
+            ("app/limits/cpu", "-2"),
+        ]);
+        kvs.extend((0..11).map(|i| KVEntry {
+            key: format!("app/tags/{}", i).into(),
+            value: format!("t{}", i).into(),
+            ..Default::default()
+        }));
+
+        let config: Config = from_entries("app/", kvs).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                name: "svc".into(),
+                enabled: true,
+                ratio: 0.5,
+                mode: Mode::Standby,
+                db: Database {
+                    host: "localhost".into(),
+                    port: 5432,
+                },
+                tags: (0..11).map(|i| format!("t{}", i)).collect(),
+                limits: [("cpu".to_string(), -2)].into(),
+                comment: None,
+                retries: 0,
+            }
+        );
+        assert_ne!(config.mode, Mode::Active);
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        let res = from_entries::<Database>(
+            "app/db",
+            entries(&[("app/db/host", "localhost"), ("app/db/port", "high")]),
+        );
+        match res {
+            Err(ConfigError::DeserializeError(key, msg)) => {
+                assert_eq!(key, "app/db/port");
+                assert_eq!(msg, "expected u16, found `high`");
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+
+        let res = from_entries::<Config>("app", entries(&[("app/db/host", "localhost")]));
+        match res {
+            Err(ConfigError::DeserializeError(key, msg)) => {
+                assert_eq!(key, "app/db");
+                assert_eq!(msg, "missing field `port`");
+            }
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+}